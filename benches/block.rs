@@ -0,0 +1,252 @@
+// frame by frame against runs of frames, for a block of 512 stereo samples
+//
+//     cargo +nightly bench --bench block
+//
+// there is no [[bench]] entry in Cargo.toml, cargo finds this file in benches/ by itself. It needs a nightly
+// compiler for #![feature(test)], and as the plugin is only built as a cdylib the modules it measures are
+// included by path
+//
+// a feedback of 1 keeps the loop from decaying into denormals while it runs
+//
+// the *_block benches take a whole block through the engine's steps while overdubbing or playing. The frame
+// by frame side is written here after the sample loop of the engine before it worked on runs, an approximation
+// of it rather than that code, which needs a host to run. The others compare single steps
+
+#![feature(test)]
+
+extern crate test;
+
+#[path = "../src/recording_buffer.rs"]
+#[allow(dead_code)]
+mod recording_buffer;
+#[path = "../src/block.rs"]
+#[allow(dead_code)]
+mod block;
+#[path = "../src/routing.rs"]
+#[allow(dead_code)]
+mod routing;
+#[path = "../src/smoother.rs"]
+#[allow(dead_code)]
+mod smoother;
+
+use test::{black_box, Bencher};
+
+use block::Block;
+use recording_buffer::{RecordingBuffer, MAX_CHANNELS};
+use routing::{Routing, RoutingMode};
+use smoother::Smoother;
+
+const BLOCK: usize = 512;
+const CHANNELS: usize = 2;
+
+fn setup() -> (RecordingBuffer, Vec<f32>, Vec<f32>) {
+    let mut buffer = RecordingBuffer::with_channels(0, CHANNELS);
+    for i in 0..BLOCK * 8 {
+        let sample = (i as f32 * 0.01).sin();
+        buffer.push(&[sample, -sample]);
+    }
+    let input = (0..BLOCK * CHANNELS).map(|i| (i as f32 * 0.003).cos()).collect();
+    let gains = (0..BLOCK).map(|i| 1.0 - i as f32 / BLOCK as f32).collect();
+    (buffer, input, gains)
+}
+
+#[bench]
+fn overdub_per_frame(b: &mut Bencher) {
+    let (mut buffer, input, _) = setup();
+    b.iter(|| {
+        for index in 0..BLOCK {
+            buffer.overdub(1000 + index, &input[index * CHANNELS..(index + 1) * CHANNELS], 0.9);
+        }
+        black_box(&buffer);
+    });
+}
+
+#[bench]
+fn overdub_frames(b: &mut Bencher) {
+    let (mut buffer, input, _) = setup();
+    b.iter(|| {
+        buffer.overdub_frames(1000, &input, 0.9);
+        black_box(&buffer);
+    });
+}
+
+#[bench]
+fn play_per_frame(b: &mut Bencher) {
+    let (buffer, _, gains) = setup();
+    let mut out = vec![0.0; BLOCK * CHANNELS];
+    b.iter(|| {
+        for index in 0..BLOCK {
+            let mut processed = [0.0; MAX_CHANNELS];
+            if let Some(old) = buffer.get(1000 + index) {
+                for (sample, sample_old) in processed.iter_mut().zip(old) {
+                    *sample = *sample_old * gains[index];
+                }
+            }
+            out[index * CHANNELS..(index + 1) * CHANNELS].copy_from_slice(&processed[..CHANNELS]);
+        }
+        black_box(&out);
+    });
+}
+
+#[bench]
+fn play_frames(b: &mut Bencher) {
+    let (buffer, _, gains) = setup();
+    let mut out = vec![0.0; BLOCK * CHANNELS];
+    b.iter(|| {
+        buffer.read_frames(1000, &mut out);
+        block::apply_gains(&mut out, CHANNELS, &gains);
+        black_box(&out);
+    });
+}
+
+#[bench]
+fn decay_per_frame(b: &mut Bencher) {
+    let (mut buffer, _, _) = setup();
+    b.iter(|| {
+        for index in 0..BLOCK {
+            buffer.decay(1000 + index, black_box(1.0));
+        }
+        black_box(&buffer);
+    });
+}
+
+#[bench]
+fn decay_frames(b: &mut Bencher) {
+    let (mut buffer, _, _) = setup();
+    b.iter(|| {
+        buffer.decay_frames(1000, BLOCK, black_box(1.0));
+        black_box(&buffer);
+    });
+}
+
+// what the engine works with besides the loop: host buffers, routing and level smoothers
+struct Engine {
+    inputs: Vec<Vec<f32>>,
+    outputs: Vec<Vec<f32>>,
+    routing: Routing,
+    input_level: Smoother,
+    loop_level: Smoother,
+    dry_level: Smoother,
+    block: Block,
+}
+
+fn engine() -> Engine {
+    let inputs = (0..CHANNELS).map(|c| (0..BLOCK).map(|i| ((i + c) as f32 * 0.003).cos()).collect()).collect();
+    let mut block = Block::default();
+    block.reserve(BLOCK);
+    Engine {
+        inputs,
+        outputs: vec![vec![0.0; BLOCK]; CHANNELS],
+        routing: Routing::new(RoutingMode::Direct, CHANNELS, CHANNELS),
+        input_level: Smoother::new(1.0),
+        loop_level: Smoother::new(1.0),
+        dry_level: Smoother::new(1.0),
+        block,
+    }
+}
+
+// a block the way the engine did it before, sample by sample: *overdub* or decay the loop at *position*
+fn block_per_sample(e: &mut Engine, buffer: &mut RecordingBuffer, position: usize, overdub: bool) {
+    let length = buffer.length();
+    for index in 0..BLOCK {
+        let input_gain = e.input_level.next();
+        let mut input = [0.0; MAX_CHANNELS];
+        for (i, sample) in input.iter_mut().enumerate().take(CHANNELS) {
+            *sample = e.inputs[i][index] * input_gain;
+        }
+        let mut frame = [0.0; MAX_CHANNELS];
+        e.routing.route(&input[..CHANNELS], &mut frame[..CHANNELS]);
+        if overdub {
+            buffer.overdub((position + index) % length, &frame[..CHANNELS], 1.0);
+        }
+    }
+    for index in 0..BLOCK {
+        let play_index = (position + index) % length;
+        let loop_gain = e.loop_level.next();
+        let dry_gain = e.dry_level.next();
+        let mut processed = [0.0; MAX_CHANNELS];
+        if let Some(old) = buffer.get(play_index) {
+            for (sample, sample_old) in processed.iter_mut().zip(old) {
+                *sample = *sample_old * loop_gain;
+            }
+        }
+        if !overdub {
+            buffer.decay(play_index, 1.0);
+        }
+        for output in 0..CHANNELS {
+            let dry = e.inputs[output][index] * dry_gain;
+            e.outputs[output][index] = processed[output % CHANNELS] + dry;
+        }
+    }
+}
+
+// the same block in runs of frames, the way the engine does it now
+fn block_runs(e: &mut Engine, buffer: &mut RecordingBuffer, position: usize, overdub: bool) {
+    for index in 0..BLOCK {
+        let input_gain = e.input_level.next();
+        let mut input = [0.0; MAX_CHANNELS];
+        for (i, sample) in input.iter_mut().enumerate().take(CHANNELS) {
+            *sample = e.inputs[i][index] * input_gain;
+        }
+        let frame = &mut e.block.input[index * CHANNELS..(index + 1) * CHANNELS];
+        e.routing.route(&input[..CHANNELS], frame);
+    }
+    if overdub {
+        buffer.overdub_frames(position, &e.block.input[..BLOCK * CHANNELS], 1.0);
+    }
+    e.loop_level.fill(&mut e.block.loop_gains[..BLOCK]);
+    e.dry_level.fill(&mut e.block.dry_gains[..BLOCK]);
+    buffer.read_frames(position, &mut e.block.play[..BLOCK * CHANNELS]);
+    if !overdub {
+        buffer.decay_frames(position, BLOCK, 1.0);
+    }
+    block::apply_gains(&mut e.block.play[..BLOCK * CHANNELS], CHANNELS, &e.block.loop_gains[..BLOCK]);
+    for index in 0..BLOCK {
+        let processed = &e.block.play[index * CHANNELS..(index + 1) * CHANNELS];
+        let dry_gain = e.block.dry_gains[index];
+        for output in 0..CHANNELS {
+            let dry = e.inputs[output][index] * dry_gain;
+            e.outputs[output][index] = processed[output % CHANNELS] + dry;
+        }
+    }
+}
+
+#[bench]
+fn overdub_block_per_sample(b: &mut Bencher) {
+    let (mut buffer, _, _) = setup();
+    let mut e = engine();
+    b.iter(|| {
+        block_per_sample(&mut e, &mut buffer, 1000, true);
+        black_box(&e.outputs);
+    });
+}
+
+#[bench]
+fn overdub_block_runs(b: &mut Bencher) {
+    let (mut buffer, _, _) = setup();
+    let mut e = engine();
+    b.iter(|| {
+        block_runs(&mut e, &mut buffer, 1000, true);
+        black_box(&e.outputs);
+    });
+}
+
+#[bench]
+fn play_block_per_sample(b: &mut Bencher) {
+    let (mut buffer, _, _) = setup();
+    let mut e = engine();
+    b.iter(|| {
+        block_per_sample(&mut e, &mut buffer, 1000, false);
+        black_box(&e.outputs);
+    });
+}
+
+#[bench]
+fn play_block_runs(b: &mut Bencher) {
+    let (mut buffer, _, _) = setup();
+    let mut e = engine();
+    b.iter(|| {
+        block_runs(&mut e, &mut buffer, 1000, false);
+        black_box(&e.outputs);
+    });
+}
@@ -0,0 +1,241 @@
+// mapping of midi notes to looper commands
+//
+// The bindings can be changed in bindings.txt in the PlexLooper folder, one binding per line:
+//
+//     # note command [velocity target] [velocity curve] [gesture]
+//     69 Record
+//     69 Clear long
+//     64 Overdub input soft
+//     65 Play loop linear
+//
+// velocity targets: off, input (input level), loop (loop level), a command without a target goes back to the
+// levels the host set. A crossfade length target is out of scope, the engine has no crossfades to set it for
+// velocity curves: linear, soft, hard, fixed
+// gestures: tap (the default), long (held down), double (pressed twice quickly), can be anywhere after the command
+
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::str::FromStr;
+
+use looper_fsm::Commands;
+use gesture::Gesture;
+
+/// what the velocity of a note is used for
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum VelocityTarget {
+    Off,
+    InputLevel,
+    LoopLevel,
+}
+
+impl FromStr for VelocityTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<VelocityTarget, String> {
+        match s {
+            "off" => Ok(VelocityTarget::Off),
+            "input" => Ok(VelocityTarget::InputLevel),
+            "loop" => Ok(VelocityTarget::LoopLevel),
+            _ => Err(format!("unknown velocity target: {}", s)),
+        }
+    }
+}
+
+/// how the velocity is turned into a level
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum VelocityCurve {
+    Linear,
+    // loud quickly
+    Soft,
+    // needs to be played hard to get loud
+    Hard,
+    // always full level
+    Fixed,
+}
+
+impl VelocityCurve {
+    /// map a midi velocity to a level between 0 and 1
+    pub fn apply(&self, velocity: u8) -> f32 {
+        self.shape(velocity.min(127) as f32 / 127.)
+    }
+
+    /// the curve for a *level* between 0 and 1
+    pub fn shape(&self, level: f32) -> f32 {
+        let level = level.clamp(0., 1.);
+        match *self {
+            VelocityCurve::Linear => level,
+            VelocityCurve::Soft => level.sqrt(),
+            VelocityCurve::Hard => level * level,
+            VelocityCurve::Fixed => 1.0,
+        }
+    }
+}
+
+impl FromStr for VelocityCurve {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<VelocityCurve, String> {
+        match s {
+            "linear" => Ok(VelocityCurve::Linear),
+            "soft" => Ok(VelocityCurve::Soft),
+            "hard" => Ok(VelocityCurve::Hard),
+            "fixed" => Ok(VelocityCurve::Fixed),
+            _ => Err(format!("unknown velocity curve: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct NoteBinding {
+    pub note: u8,
+    // the midi note this command is bound to
+    pub command: Commands,
+    pub velocity: VelocityTarget,
+    pub curve: VelocityCurve,
+    pub gesture: Gesture,
+}
+
+impl NoteBinding {
+    pub fn new(note: u8, command: Commands) -> NoteBinding {
+        NoteBinding { note, command, velocity: VelocityTarget::Off, curve: VelocityCurve::Linear, gesture: Gesture::Tap }
+    }
+
+    pub fn with_gesture(note: u8, command: Commands, gesture: Gesture) -> NoteBinding {
+        NoteBinding { gesture, ..NoteBinding::new(note, command) }
+    }
+
+    /// parse a line of the bindings file
+    pub fn parse(line: &str) -> Result<NoteBinding, String> {
+        let mut words = line.split_whitespace();
+        let note = words.next().ok_or("missing note")?
+            .parse::<u8>().map_err(|e| e.to_string())?;
+        let command = words.next().ok_or("missing command")?.parse::<Commands>()?;
+        let mut binding = NoteBinding::new(note, command);
+        // the gesture can be anywhere, the velocity target and curve come in order
+        let mut rest = Vec::new();
+        for word in words {
+            match word.parse::<Gesture>() {
+                Ok(gesture) => binding.gesture = gesture,
+                Err(_) => rest.push(word),
+            }
+        }
+        let mut words = rest.into_iter();
+        if let Some(target) = words.next() {
+            binding.velocity = target.parse()?;
+        }
+        if let Some(curve) = words.next() {
+            binding.curve = curve.parse()?;
+        }
+        if let Some(word) = words.next() {
+            return Err(format!("unexpected: {}", word));
+        }
+        Ok(binding)
+    }
+}
+
+impl FromStr for NoteBinding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<NoteBinding, String> {
+        NoteBinding::parse(s)
+    }
+}
+
+/// the bindings used when there is no bindings file
+pub fn default_bindings() -> Vec<NoteBinding> {
+    vec![
+        NoteBinding::new(69, Commands::Record),  // A3
+        NoteBinding::with_gesture(69, Commands::Clear, Gesture::LongPress),
+        NoteBinding::new(67, Commands::Stop),  // G3
+        NoteBinding::with_gesture(67, Commands::Reset, Gesture::DoubleTap),
+        NoteBinding::new(65, Commands::Play),  // F3
+        NoteBinding::new(64, Commands::Overdub),  // E3
+        NoteBinding::new(62, Commands::ReplaceStart),  // D3
+        NoteBinding::new(60, Commands::Mute),  // C3
+        NoteBinding::new(59, Commands::InsertStart),  // B2
+        NoteBinding::new(57, Commands::MultiplyStart),  // A2
+        NoteBinding::new(71, Commands::Window),  // B3
+        NoteBinding::new(72, Commands::WindowBackward),  // C4
+        NoteBinding::new(74, Commands::WindowForward),  // D4
+        NoteBinding::new(76, Commands::WindowShrink),  // E4
+        NoteBinding::new(77, Commands::WindowGrow),  // F4
+        NoteBinding::new(79, Commands::WindowCycleBackward),  // G4
+        NoteBinding::new(81, Commands::WindowCycleForward),  // A4
+        NoteBinding::new(83, Commands::StutterStart),  // B4
+        NoteBinding::new(84, Commands::ShufflePrint),  // C5
+        NoteBinding::new(86, Commands::Undo),  // D5
+    ]
+}
+
+/// the binding of *note* for *gesture*
+pub fn find_binding(bindings: &[NoteBinding], note: u8, gesture: Gesture) -> Option<NoteBinding> {
+    bindings.iter().find(|b| b.note == note && b.gesture == gesture).cloned()
+}
+
+/// read the bindings from *path*, lines that can't be parsed are logged and skipped
+pub fn load_bindings(path: &Path) -> Option<Vec<NoteBinding>> {
+    let file = File::open(path).ok()?;
+    let mut bindings = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.ok()?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match NoteBinding::parse(line) {
+            Ok(binding) => bindings.push(binding),
+            Err(e) => info!("skipping binding '{}': {}", line, e),
+        }
+    }
+    Some(bindings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let b = NoteBinding::parse("64 Overdub input soft").unwrap();
+        assert_eq!(b.note, 64);
+        assert!(b.command == Commands::Overdub);
+        assert_eq!(b.velocity, VelocityTarget::InputLevel);
+        assert_eq!(b.curve, VelocityCurve::Soft);
+    }
+
+    #[test]
+    fn test_parse_defaults() {
+        let b = NoteBinding::parse("69 Record").unwrap();
+        assert_eq!(b.velocity, VelocityTarget::Off);
+        assert_eq!(b.curve, VelocityCurve::Linear);
+        assert_eq!(b.gesture, Gesture::Tap);
+    }
+
+    #[test]
+    fn test_parse_gesture() {
+        let b = NoteBinding::parse("69 Clear long").unwrap();
+        assert_eq!(b.gesture, Gesture::LongPress);
+        let b = NoteBinding::parse("64 Overdub double input").unwrap();
+        assert_eq!(b.gesture, Gesture::DoubleTap);
+        assert_eq!(b.velocity, VelocityTarget::InputLevel);
+        assert!(NoteBinding::parse("64 Overdub input soft loud").is_err());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(NoteBinding::parse("").is_err());
+        assert!(NoteBinding::parse("300 Record").is_err());
+        assert!(NoteBinding::parse("60 Dance").is_err());
+        assert!(NoteBinding::parse("60 Play loud").is_err());
+    }
+
+    #[test]
+    fn test_curves() {
+        assert_eq!(VelocityCurve::Linear.apply(0), 0.0);
+        assert_eq!(VelocityCurve::Linear.apply(127), 1.0);
+        assert_eq!(VelocityCurve::Fixed.apply(0), 1.0);
+        assert!(VelocityCurve::Soft.apply(64) > VelocityCurve::Linear.apply(64));
+        assert!(VelocityCurve::Hard.apply(64) < VelocityCurve::Linear.apply(64));
+    }
+}
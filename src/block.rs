@@ -0,0 +1,82 @@
+// the audio of one block, the engine works on runs of frames instead of single samples
+//
+// frames are interleaved in loop channels like in the loop, so a run of them goes to and from the loop
+// in one slice operation, loops the compiler can vectorize
+
+use recording_buffer::MAX_CHANNELS;
+
+#[derive(Default)]
+pub struct Block {
+    pub input: Vec<f32>,
+    // the input routed to the loop channels
+    pub play: Vec<f32>,
+    // what the loop plays
+    pub loop_gains: Vec<f32>,
+    pub dry_gains: Vec<f32>,
+    pub clicks: Vec<f32>,
+}
+
+impl Block {
+    /// make room for blocks of up to *samples* samples, when the host sets the block size
+    pub fn reserve(&mut self, samples: usize) {
+        if self.clicks.len() < samples {
+            self.input.resize(samples * MAX_CHANNELS, 0.0);
+            self.play.resize(samples * MAX_CHANNELS, 0.0);
+            self.loop_gains.resize(samples, 0.0);
+            self.dry_gains.resize(samples, 0.0);
+            self.clicks.resize(samples, 0.0);
+        }
+    }
+}
+
+/// multiply every frame of *frames* by its gain, a fade when the gains change
+pub fn apply_gains(frames: &mut [f32], channels: usize, gains: &[f32]) {
+    // the common cases with a fixed frame size
+    match channels {
+        1 => for (sample, gain) in frames.iter_mut().zip(gains) {
+            *sample *= gain;
+        },
+        2 => for (frame, gain) in frames.chunks_exact_mut(2).zip(gains) {
+            frame[0] *= gain;
+            frame[1] *= gain;
+        },
+        _ => for (frame, gain) in frames.chunks_mut(channels.max(1)).zip(gains) {
+            for sample in frame.iter_mut() {
+                *sample *= gain;
+            }
+        },
+    }
+}
+
+/// set every sample of *frames* to 0
+pub fn silence(frames: &mut [f32]) {
+    for sample in frames.iter_mut() {
+        *sample = 0.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_gains() {
+        let mut frames = [1.0; 9];
+        apply_gains(&mut frames, 3, &[1.0, 0.5, 0.0]);
+        assert_eq!(frames, [1.0, 1.0, 1.0, 0.5, 0.5, 0.5, 0.0, 0.0, 0.0]);
+        let mut mono = [1.0; 3];
+        apply_gains(&mut mono, 1, &[0.5, 0.5, 0.5]);
+        assert_eq!(mono, [0.5; 3]);
+    }
+
+    #[test]
+    fn test_reserve() {
+        let mut block = Block::default();
+        block.reserve(64);
+        assert_eq!(block.input.len(), 64 * MAX_CHANNELS);
+        assert_eq!(block.clicks.len(), 64);
+        // never shrinks
+        block.reserve(32);
+        assert_eq!(block.clicks.len(), 64);
+    }
+}
@@ -0,0 +1,216 @@
+// midi controllers (CC, pitch bend and aftertouch) assigned to parameters
+//
+// The assignments are read from controllers.txt in the PlexLooper folder, one per line:
+//
+//     # source parameter [min max] [curve] [takeover]
+//     cc 11 feedback 0.5 1.0 soft takeover
+//     bend looplevel
+//     aftertouch stutterslice 0 2
+//
+// sources: cc <number>, bend, aftertouch
+// parameters are named like in OSC: lower case without spaces, min and max are in the units of the parameter
+// and are kept inside its range (max can be lower than min to turn the controller around), the curves are the
+// velocity curves of the bindings. Speed and the loop window aren't parameters: half speed is an insert mode
+// and the window moves in steps by commands, so neither can be put on a controller.
+// With takeover the controller only moves the parameter once it has come to the value the parameter has,
+// so it doesn't jump when the parameter was changed somewhere else.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use easyvst::ParamDef;
+use bindings::VelocityCurve;
+
+// how close (of the range) a controller has to come to the parameter to take it over
+const TAKEOVER_RANGE: f32 = 0.02;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ControlSource {
+    Cc(u8),
+    PitchBend,
+    Aftertouch,
+}
+
+impl ControlSource {
+    /// the source of a midi message and its value between 0 and 1, the channel is ignored
+    pub fn from_midi(data: [u8; 3]) -> Option<(ControlSource, f32)> {
+        match data[0] & 0xF0 {
+            0xB0 => Some((ControlSource::Cc(data[1]), data[2].min(127) as f32 / 127.)),
+            0xE0 => {
+                let bend = (data[2] as u16 & 0x7F) << 7 | data[1] as u16 & 0x7F;
+                Some((ControlSource::PitchBend, bend as f32 / 16383.))
+            }
+            // channel pressure, or the pressure of a key
+            0xD0 => Some((ControlSource::Aftertouch, data[1].min(127) as f32 / 127.)),
+            0xA0 => Some((ControlSource::Aftertouch, data[2].min(127) as f32 / 127.)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Controller {
+    pub source: ControlSource,
+    pub param: usize,
+    // index of the parameter
+    pub min: f32,
+    pub max: f32,
+    pub curve: VelocityCurve,
+    pub takeover: bool,
+    engaged: bool,
+    // the controller has picked up the parameter
+    last: Option<f32>,
+    // where the controller was last time
+    sent: Option<f32>,
+    // the value the controller gave the parameter
+}
+
+impl Controller {
+    pub fn new(source: ControlSource, param: usize, min: f32, max: f32) -> Controller {
+        Controller {
+            source,
+            param,
+            min,
+            max,
+            curve: VelocityCurve::Linear,
+            takeover: false,
+            engaged: false,
+            last: None,
+            sent: None,
+        }
+    }
+
+    /// parse a line of the controllers file, *params* are the plugin parameters
+    pub fn parse(line: &str, params: &[ParamDef]) -> Result<Controller, String> {
+        let mut words = line.split_whitespace();
+        let source = match words.next().ok_or("missing source")? {
+            "cc" => ControlSource::Cc(words.next().ok_or("missing cc number")?
+                .parse::<u8>().map_err(|e| e.to_string())?.min(127)),
+            "bend" => ControlSource::PitchBend,
+            "aftertouch" => ControlSource::Aftertouch,
+            source => return Err(format!("unknown source: {}", source)),
+        };
+        let name = words.next().ok_or("missing parameter")?;
+        let param = params.iter().position(|p| p.name.to_lowercase().replace(" ", "") == name)
+            .ok_or(format!("unknown parameter: {}", name))?;
+        let mut controller = Controller::new(source, param, params[param].min, params[param].max);
+
+        let words: Vec<&str> = words.collect();
+        let mut rest = &words[..];
+        if let Ok(min) = rest.first().map_or(Err(()), |w| w.parse::<f32>().map_err(|_| ())) {
+            let max = rest.get(1).ok_or("missing max")?.parse::<f32>().map_err(|e| e.to_string())?;
+            let (low, high) = (params[param].min, params[param].max);
+            controller.min = min.clamp(low, high);
+            controller.max = max.clamp(low, high);
+            rest = &rest[2..];
+        }
+        for word in rest {
+            match *word {
+                "takeover" => controller.takeover = true,
+                curve => controller.curve = curve.parse()?,
+            }
+        }
+        Ok(controller)
+    }
+
+    /// the new value of the parameter for controller *input* (0 to 1), when the parameter is at *current*,
+    /// None while the controller hasn't taken over the parameter
+    pub fn value(&mut self, input: f32, current: f32) -> Option<f32> {
+        let target = self.min + (self.max - self.min) * self.curve.shape(input);
+        if self.takeover {
+            // the parameter was changed somewhere else, it has to be picked up again
+            let range = (self.max - self.min).abs();
+            if self.sent.is_none_or(|sent| (sent - current).abs() > range * 0.001) {
+                self.engaged = false;
+            }
+            if !self.engaged {
+                let close = (target - current).abs() <= TAKEOVER_RANGE * range;
+                let crossed = self.last.is_some_and(|last| (last - current) * (target - current) <= 0.);
+                self.last = Some(target);
+                if !close && !crossed {
+                    return None;
+                }
+                self.engaged = true;
+            }
+        }
+        self.last = Some(target);
+        self.sent = Some(target);
+        Some(target)
+    }
+}
+
+/// read the controller assignments from *path*, lines that can't be parsed are logged and skipped
+pub fn load_controllers(path: &Path, params: &[ParamDef]) -> Vec<Controller> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return Vec::new(),
+    };
+    let mut controllers = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match Controller::parse(line, params) {
+            Ok(controller) => controllers.push(controller),
+            Err(e) => info!("skipping controller '{}': {}", line, e),
+        }
+    }
+    controllers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params() -> Vec<ParamDef> {
+        vec![ParamDef::new("Feedback", 0.0, 1.0, 1.0), ParamDef::new("Loop Level", 0.0, 1.0, 1.0),
+             ParamDef::new("Division", 1.0, 8.0, 8.0)]
+    }
+
+    #[test]
+    fn test_parse() {
+        let c = Controller::parse("cc 11 feedback 0.5 1.0 soft takeover", &params()).unwrap();
+        assert_eq!(c.source, ControlSource::Cc(11));
+        assert_eq!(c.param, 0);
+        assert_eq!((c.min, c.max), (0.5, 1.0));
+        assert_eq!(c.curve, VelocityCurve::Soft);
+        assert!(c.takeover);
+        let c = Controller::parse("bend looplevel", &params()).unwrap();
+        assert_eq!((c.param, c.min, c.max, c.takeover), (1, 0.0, 1.0, false));
+        assert!(Controller::parse("cc feedback", &params()).is_err());
+        assert!(Controller::parse("bend volume", &params()).is_err());
+        assert!(Controller::parse("bend feedback 0.5", &params()).is_err());
+        // out of range, the wrong way around
+        let c = Controller::parse("cc 1 division 16 0", &params()).unwrap();
+        assert_eq!((c.param, c.min, c.max), (2, 8.0, 1.0));
+    }
+
+    #[test]
+    fn test_from_midi() {
+        assert_eq!(ControlSource::from_midi([0xE3, 0x7F, 0x7F]), Some((ControlSource::PitchBend, 1.0)));
+        assert_eq!(ControlSource::from_midi([0xD0, 0, 0]), Some((ControlSource::Aftertouch, 0.0)));
+        assert_eq!(ControlSource::from_midi([0x90, 60, 100]), None);
+        // any channel
+        assert_eq!(ControlSource::from_midi([0xB1, 7, 127]), Some((ControlSource::Cc(7), 1.0)));
+    }
+
+    #[test]
+    fn test_takeover() {
+        let mut c = Controller::new(ControlSource::Cc(1), 0, 0., 1.);
+        c.takeover = true;
+        // the parameter is at 0.5, the pedal comes up from below
+        assert_eq!(c.value(0.1, 0.5), None);
+        assert_eq!(c.value(0.3, 0.5), None);
+        assert_eq!(c.value(0.6, 0.5), Some(0.6));
+        assert_eq!(c.value(0.2, 0.6), Some(0.2));
+        // the host moved the parameter
+        assert_eq!(c.value(0.3, 0.9), None);
+        assert_eq!(c.value(0.9, 0.9), Some(0.9));
+    }
+}
@@ -0,0 +1,417 @@
+// loop storage on disk, for loops that are too long to keep in memory
+//
+// the loop is split into pages, only the pages around the play position are kept in memory.
+// a background thread loads the pages ahead of the play position and writes back the pages
+// that were changed, the audio thread never waits for it: a frame that isn't loaded (yet) is
+// played as silence and writes to it are dropped
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use recording_buffer::{LoopStore, MAX_CHANNELS};
+use spsc;
+
+/// frames in a page
+pub const PAGE_FRAMES: usize = 1 << 15;
+// pages kept in memory, including the ones on their way to or from the disk
+const RESIDENT_PAGES: usize = 16;
+// pages loaded ahead of the play position
+const PREFETCH: usize = 4;
+// 2^16 pages of 2^15 frames, more than 12 hours at 48kHz
+const MAX_PAGES: usize = 1 << 16;
+
+// tells the instances in a process apart
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+// generation, page, channels and the page's samples
+enum Request {
+    Load(usize, usize, usize, Box<[f32]>),
+    Store(usize, usize, usize, Box<[f32]>),
+}
+
+enum Response {
+    Loaded(usize, usize, Box<[f32]>),
+    Stored(usize, usize, Box<[f32]>),
+}
+
+enum Slot {
+    // the page is (only) in the file
+    OnDisk,
+    Loading,
+    Storing,
+    // in memory, and changed since it was loaded
+    Ready(Box<[f32]>, bool),
+}
+
+pub struct DiskStore {
+    channels: usize,
+    length: usize,
+    slots: Vec<Slot>,
+    // empty pages, so the audio thread doesn't have to allocate
+    // they have room for MAX_CHANNELS, so the channels can change without new pages
+    free: Vec<Box<[f32]>>,
+    // increased on clear, so answers for the old loop are ignored
+    generation: usize,
+    requests: spsc::Producer<Request>,
+    responses: spsc::Consumer<Response>,
+    running: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+    path: PathBuf,
+}
+
+impl DiskStore {
+    /// create a store for a loop of *channels* channels in a new file in *folder*
+    pub fn create(folder: &Path, channels: usize) -> io::Result<DiskStore> {
+        fs::create_dir_all(folder)?;
+        let channels = channels.clamp(1, MAX_CHANNELS);
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let path = folder.join(format!("loop-{}-{}.raw", process::id(), id));
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path)?;
+
+        let (requests, thread_requests) = spsc::channel(RESIDENT_PAGES);
+        let (thread_responses, responses) = spsc::channel(RESIDENT_PAGES);
+        let running = Arc::new(AtomicBool::new(true));
+        let thread_running = running.clone();
+        let thread = thread::Builder::new()
+            .name("plexlooper disk".to_string())
+            .spawn(move || {
+                let mut io = DiskThread { file, requests: thread_requests, responses: thread_responses };
+                io.run(&thread_running);
+            })?;
+
+        let mut free = Vec::with_capacity(RESIDENT_PAGES);
+        for _ in 0..RESIDENT_PAGES {
+            free.push(vec![0.0; PAGE_FRAMES * MAX_CHANNELS].into_boxed_slice());
+        }
+        let mut slots = Vec::with_capacity(MAX_PAGES);
+        slots.push(Slot::OnDisk);
+        info!("disk loop in {:?}", path);
+        Ok(DiskStore {
+            channels,
+            length: 0,
+            slots,
+            free,
+            generation: 0,
+            requests,
+            responses,
+            running,
+            thread: Some(thread),
+            path,
+        })
+    }
+
+    /// forget the loop, the next recording starts from the beginning with *channels* channels
+    pub fn clear(&mut self, channels: usize) {
+        self.channels = channels.clamp(1, MAX_CHANNELS);
+        self.generation += 1;
+        self.length = 0;
+        for slot in self.slots.iter_mut() {
+            if let Slot::Ready(page, _) = mem::replace(slot, Slot::OnDisk) {
+                self.free.push(page);
+            }
+        }
+        self.slots.truncate(1);
+    }
+
+    /// handle the answers of the disk thread and make sure the pages around *position* are loaded,
+    /// called once per block
+    pub fn update(&mut self, position: usize) {
+        while let Some(response) = self.responses.pop() {
+            match response {
+                Response::Loaded(generation, page, data) | Response::Stored(generation, page, data) => {
+                    if generation == self.generation && page < self.slots.len() {
+                        self.slots[page] = Slot::Ready(data, false);
+                    } else {
+                        self.free.push(data);
+                    }
+                }
+            }
+        }
+
+        let pages = self.pages();
+        if pages == 0 {
+            return;
+        }
+        let current = position / PAGE_FRAMES % pages;
+        // one page behind the play position for writes that are compensated for latency
+        let wanted = |page: usize| {
+            let ahead = (page + pages - current) % pages;
+            ahead <= PREFETCH || ahead == pages - 1
+        };
+        // the page we are recording into stays
+        let last = self.slots.len() - 1;
+
+        for page in 0..self.slots.len() {
+            if page == last || wanted(page) {
+                continue;
+            }
+            if let Slot::Ready(..) = self.slots[page] {
+                if let Slot::Ready(data, dirty) = mem::replace(&mut self.slots[page], Slot::OnDisk) {
+                    if dirty {
+                        match self.requests.push(Request::Store(self.generation, page, self.channels, data)) {
+                            Ok(()) => self.slots[page] = Slot::Storing,
+                            Err(Request::Store(_, _, _, data)) | Err(Request::Load(_, _, _, data)) => {
+                                self.slots[page] = Slot::Ready(data, true)
+                            }
+                        }
+                    } else {
+                        self.free.push(data);
+                    }
+                }
+            }
+        }
+
+        for ahead in 0..PREFETCH + 1 {
+            let page = (current + ahead) % pages;
+            if let Slot::OnDisk = self.slots[page] {
+                if let Some(data) = self.free.pop() {
+                    match self.requests.push(Request::Load(self.generation, page, self.channels, data)) {
+                        Ok(()) => self.slots[page] = Slot::Loading,
+                        Err(Request::Load(_, _, _, data)) | Err(Request::Store(_, _, _, data)) => {
+                            self.free.push(data);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// how many pages the loop has
+    fn pages(&self) -> usize {
+        self.length.div_ceil(PAGE_FRAMES)
+    }
+
+    fn page(&self, idx: usize) -> Option<&[f32]> {
+        if idx >= self.length {
+            return None;
+        }
+        match self.slots.get(idx / PAGE_FRAMES) {
+            Some(Slot::Ready(data, _)) => Some(data),
+            _ => None,
+        }
+    }
+
+    fn page_mut(&mut self, idx: usize) -> Option<&mut [f32]> {
+        if idx >= self.length {
+            return None;
+        }
+        match self.slots.get_mut(idx / PAGE_FRAMES) {
+            Some(&mut Slot::Ready(ref mut data, ref mut dirty)) => {
+                *dirty = true;
+                Some(data)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl LoopStore for DiskStore {
+    fn channels(&self) -> usize {
+        self.channels
+    }
+
+    fn length(&self) -> usize {
+        self.length
+    }
+
+    fn get(&self, idx: usize) -> Option<&[f32]> {
+        let channels = self.channels;
+        let start = idx % PAGE_FRAMES * channels;
+        self.page(idx).map(|data| &data[start..start + channels])
+    }
+
+    fn get_mut(&mut self, idx: usize) -> Option<&mut [f32]> {
+        let channels = self.channels;
+        let start = idx % PAGE_FRAMES * channels;
+        self.page_mut(idx).map(|data| &mut data[start..start + channels])
+    }
+
+    fn push(&mut self, frame: &[f32]) {
+        if self.length == MAX_PAGES * PAGE_FRAMES {
+            return;
+        }
+        if self.length > 0 && self.length.is_multiple_of(PAGE_FRAMES) {
+            self.slots.push(Slot::OnDisk);
+        }
+        let last = self.slots.len() - 1;
+        if let Slot::OnDisk = self.slots[last] {
+            // a new page, there is nothing on disk yet
+            if let Some(mut data) = self.free.pop() {
+                for sample in data.iter_mut() {
+                    *sample = 0.0;
+                }
+                self.slots[last] = Slot::Ready(data, true);
+            }
+        }
+        self.length += 1;
+        let idx = self.length - 1;
+        if let Some(old) = self.get_mut(idx) {
+            old.copy_from_slice(&frame[..old.len()]);
+        }
+    }
+
+    fn overwrite(&mut self, idx: usize, frame: &[f32]) {
+        if let Some(old) = self.get_mut(idx) {
+            old.copy_from_slice(&frame[..old.len()]);
+        }
+    }
+
+    fn overdub(&mut self, idx: usize, frame: &[f32], feedback: f32) {
+        const WET_MULT: f32 = 0.98;
+        if let Some(old) = self.get_mut(idx) {
+            for (sample, sample_in) in old.iter_mut().zip(frame) {
+                *sample = (*sample * WET_MULT) * feedback + sample_in;
+            }
+        }
+    }
+
+    fn decay(&mut self, idx: usize, feedback: f32) {
+        if let Some(old) = self.get_mut(idx) {
+            for sample in old.iter_mut() {
+                *sample *= feedback;
+            }
+        }
+    }
+
+    fn insert_empty(&mut self, _at: usize, _size: usize) {
+        // moving everything after *at* on disk can't be done in time, inserting isn't available
+    }
+
+    fn remove(&mut self, _at: usize, _size: usize) {
+    }
+}
+
+impl Drop for DiskStore {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+struct DiskThread {
+    file: File,
+    requests: spsc::Consumer<Request>,
+    responses: spsc::Producer<Response>,
+}
+
+impl DiskThread {
+    fn run(&mut self, running: &AtomicBool) {
+        while running.load(Ordering::Relaxed) {
+            match self.requests.pop() {
+                Some(Request::Load(generation, page, channels, mut data)) => {
+                    if let Err(e) = self.load(page, &mut data[..PAGE_FRAMES * channels]) {
+                        info!("can't load page {}: {}", page, e);
+                    }
+                    self.respond(Response::Loaded(generation, page, data));
+                }
+                Some(Request::Store(generation, page, channels, data)) => {
+                    if let Err(e) = self.store(page, &data[..PAGE_FRAMES * channels]) {
+                        info!("can't store page {}: {}", page, e);
+                    }
+                    self.respond(Response::Stored(generation, page, data));
+                }
+                None => thread::sleep(Duration::from_millis(1)),
+            }
+        }
+    }
+
+    fn respond(&mut self, response: Response) {
+        // there are never more pages in flight than fit in the queue
+        let mut response = response;
+        while let Err(r) = self.responses.push(response) {
+            response = r;
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// where *page* starts in the file, *data* is what the page holds
+    fn offset(page: usize, data: &[f32]) -> u64 {
+        (page * data.len() * 4) as u64
+    }
+
+    fn load(&mut self, page: usize, data: &mut [f32]) -> io::Result<()> {
+        let mut bytes = vec![0u8; data.len() * 4];
+        self.file.seek(SeekFrom::Start(DiskThread::offset(page, data)))?;
+        // the end of the file reads as silence
+        let mut read = 0;
+        loop {
+            let n = self.file.read(&mut bytes[read..])?;
+            if n == 0 {
+                break;
+            }
+            read += n;
+        }
+        for (sample, b) in data.iter_mut().zip(bytes.chunks(4)) {
+            let bits = b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24;
+            *sample = f32::from_bits(bits);
+        }
+        Ok(())
+    }
+
+    fn store(&mut self, page: usize, data: &[f32]) -> io::Result<()> {
+        let mut bytes = Vec::with_capacity(data.len() * 4);
+        for sample in data {
+            let bits = sample.to_bits();
+            bytes.extend_from_slice(&[bits as u8, (bits >> 8) as u8, (bits >> 16) as u8, (bits >> 24) as u8]);
+        }
+        self.file.seek(SeekFrom::Start(DiskThread::offset(page, data)))?;
+        self.file.write_all(&bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    // let the disk thread catch up
+    fn settle(store: &mut DiskStore, position: usize) {
+        for _ in 0..20 {
+            store.update(position);
+            thread::sleep(Duration::from_millis(2));
+        }
+    }
+
+    #[test]
+    fn test_record_and_play_back() {
+        let folder = env::temp_dir().join("plexlooper-test");
+        let mut store = DiskStore::create(&folder, 2).unwrap();
+        // longer than fits in memory, so pages have to go to disk and come back
+        let length = PAGE_FRAMES * (RESIDENT_PAGES + 4);
+        for n in 0..length {
+            if n % PAGE_FRAMES == 0 {
+                settle(&mut store, n);
+            }
+            let value = (n / PAGE_FRAMES) as f32;
+            store.push(&[value, -value]);
+        }
+        assert_eq!(store.length(), length);
+
+        for page in 0..length / PAGE_FRAMES {
+            let position = page * PAGE_FRAMES;
+            settle(&mut store, position);
+            assert_eq!(store.get(position + 1), Some(&[page as f32, -(page as f32)][..]));
+        }
+
+        store.clear(2);
+        assert_eq!(store.length(), 0);
+        assert_eq!(store.get(0), None);
+
+        // the next loop has other channels, the pages stay
+        store.clear(3);
+        store.push(&[1.0, 2.0, 3.0]);
+        assert_eq!(store.channels(), 3);
+        assert_eq!(store.get(0), Some(&[1.0, 2.0, 3.0][..]));
+    }
+}
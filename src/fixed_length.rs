@@ -0,0 +1,117 @@
+// fixed length recording: the length of a new loop is set up front (bars at a tempo, or seconds)
+// instead of by pressing Record a second time
+
+use std::fmt;
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum FixedMode {
+    // the loop is as long as Record is pressed the second time
+    #[default]
+    Off,
+    Bars,
+    Seconds,
+}
+
+impl From<usize> for FixedMode {
+    fn from(val: usize) -> FixedMode {
+        match val {
+            1 => FixedMode::Bars,
+            2 => FixedMode::Seconds,
+            _ => FixedMode::Off,
+        }
+    }
+}
+
+impl fmt::Display for FixedMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let printable = match *self {
+            FixedMode::Off => "Off",
+            FixedMode::Bars => "Bars",
+            FixedMode::Seconds => "Seconds",
+        };
+        write!(f, "{}", printable)
+    }
+}
+
+/// what happens when a fixed length recording is done
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum AfterRecord {
+    #[default]
+    Play,
+    Overdub,
+    Stop,
+}
+
+impl From<usize> for AfterRecord {
+    fn from(val: usize) -> AfterRecord {
+        match val {
+            1 => AfterRecord::Overdub,
+            2 => AfterRecord::Stop,
+            _ => AfterRecord::Play,
+        }
+    }
+}
+
+impl fmt::Display for AfterRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let printable = match *self {
+            AfterRecord::Play => "Play",
+            AfterRecord::Overdub => "Overdub",
+            AfterRecord::Stop => "Stop",
+        };
+        write!(f, "{}", printable)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct FixedLength {
+    pub mode: FixedMode,
+    pub bpm: f32,
+    pub bars: usize,
+    pub seconds: f32,
+    pub after: AfterRecord,
+}
+
+impl Default for FixedLength {
+    fn default() -> FixedLength {
+        FixedLength { mode: FixedMode::Off, bpm: 120., bars: 4, seconds: 10., after: AfterRecord::Play }
+    }
+}
+
+impl FixedLength {
+    /// the length of a new loop in samples, None if it is set by pressing Record
+    pub fn length(&self, beats_per_bar: usize, sample_rate: f64) -> Option<usize> {
+        let seconds = match self.mode {
+            FixedMode::Off => return None,
+            FixedMode::Bars => {
+                if self.bpm <= 0. {
+                    return None;
+                }
+                (self.bars * beats_per_bar) as f64 * 60. / self.bpm as f64
+            }
+            FixedMode::Seconds => self.seconds as f64,
+        };
+        let length = (seconds * sample_rate).round() as usize;
+        if length > 0 { Some(length) } else { None }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_length() {
+        let mut f = FixedLength::default();
+        assert_eq!(f.length(4, 48000.), None);
+        f.mode = FixedMode::Bars;
+        f.bpm = 90.;
+        f.bars = 2;
+        assert_eq!(f.length(3, 44100.), Some(176400));
+        f.mode = FixedMode::Seconds;
+        f.seconds = 1.5;
+        assert_eq!(f.length(4, 48000.), Some(72000));
+        f.seconds = 0.;
+        assert_eq!(f.length(4, 48000.), None);
+    }
+}
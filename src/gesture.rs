@@ -0,0 +1,146 @@
+// tap, long-press and double-tap on a note, so a few footswitches can do more
+//
+// a tap fires as soon as the note goes on, a long press fires while the note is still held,
+// the second press of a double tap fires instead of a tap. A note with a long press still taps
+// when it goes on, so the long press comes on top of the tap (holding Record clears what it recorded).
+// times are in samples, counted since the plugin started
+
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Gesture {
+    Tap,
+    LongPress,
+    DoubleTap,
+}
+
+impl fmt::Display for Gesture {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let printable = match *self {
+            Gesture::Tap => "tap",
+            Gesture::LongPress => "long",
+            Gesture::DoubleTap => "double",
+        };
+        write!(f, "{}", printable)
+    }
+}
+
+impl FromStr for Gesture {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Gesture, String> {
+        match s {
+            "tap" => Ok(Gesture::Tap),
+            "long" => Ok(Gesture::LongPress),
+            "double" => Ok(Gesture::DoubleTap),
+            _ => Err(format!("unknown gesture: {}", s)),
+        }
+    }
+}
+
+const NOTES: usize = 128;
+/// half a second at 48kHz
+pub const DEFAULT_LONG_PRESS: usize = 24000;
+pub const DEFAULT_DOUBLE_TAP: usize = 12000;
+
+pub struct Gestures {
+    pub long_press: usize,
+    // how long a note has to be held for a long press
+    pub double_tap: usize,
+    // how soon the second press of a double tap has to come
+    pressed: [Option<usize>; NOTES],
+    // when the notes that are down went on
+    last_tap: [Option<usize>; NOTES],
+    long_fired: [bool; NOTES],
+}
+
+impl Default for Gestures {
+    fn default() -> Gestures {
+        Gestures::new(DEFAULT_LONG_PRESS, DEFAULT_DOUBLE_TAP)
+    }
+}
+
+impl Gestures {
+    pub fn new(long_press: usize, double_tap: usize) -> Gestures {
+        Gestures {
+            long_press,
+            double_tap,
+            pressed: [None; NOTES],
+            last_tap: [None; NOTES],
+            long_fired: [false; NOTES],
+        }
+    }
+
+    /// *note* went on at sample *at*, is it a tap or the second press of a double tap
+    pub fn press(&mut self, note: u8, at: usize) -> Gesture {
+        let note = note as usize % NOTES;
+        self.pressed[note] = Some(at);
+        self.long_fired[note] = false;
+        match self.last_tap[note] {
+            Some(last) if at.saturating_sub(last) <= self.double_tap => {
+                // a third press starts over
+                self.last_tap[note] = None;
+                Gesture::DoubleTap
+            }
+            _ => {
+                self.last_tap[note] = Some(at);
+                Gesture::Tap
+            }
+        }
+    }
+
+    /// *note* went off
+    pub fn release(&mut self, note: u8) {
+        self.pressed[note as usize % NOTES] = None;
+    }
+
+    /// the next note that has been held long enough at sample *now*, every press is reported once
+    pub fn long_press(&mut self, now: usize) -> Option<u8> {
+        for note in 0..NOTES {
+            if let Some(at) = self.pressed[note] {
+                if !self.long_fired[note] && now.saturating_sub(at) >= self.long_press {
+                    self.long_fired[note] = true;
+                    // a long press isn't the first tap of a double tap
+                    self.last_tap[note] = None;
+                    return Some(note as u8);
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_double_tap() {
+        let mut g = Gestures::new(1000, 500);
+        assert_eq!(g.press(60, 0), Gesture::Tap);
+        g.release(60);
+        // too late
+        assert_eq!(g.press(60, 600), Gesture::Tap);
+        g.release(60);
+        assert_eq!(g.press(60, 900), Gesture::DoubleTap);
+        g.release(60);
+        assert_eq!(g.press(60, 1000), Gesture::Tap);
+        // other notes don't count
+        assert_eq!(g.press(62, 1100), Gesture::Tap);
+    }
+
+    #[test]
+    fn test_long_press() {
+        let mut g = Gestures::new(1000, 500);
+        g.press(60, 0);
+        assert_eq!(g.long_press(999), None);
+        assert_eq!(g.long_press(1000), Some(60));
+        assert_eq!(g.long_press(2000), None);
+        g.release(60);
+        // released before it was long
+        g.press(60, 3000);
+        g.release(60);
+        assert_eq!(g.long_press(5000), None);
+    }
+}
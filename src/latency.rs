@@ -0,0 +1,195 @@
+// compensation of the round trip latency of the audio interface
+//
+// what the player hears at loop position p reaches our input *samples* later, so everything that is recorded
+// is written *samples* earlier into the loop to line up with what was heard
+
+use std::fmt;
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum LatencyMode {
+    #[default]
+    Off,
+    // the latency is set in ms with the Latency parameter
+    Manual,
+    // the latency is taken from the host
+    Auto,
+}
+
+impl From<usize> for LatencyMode {
+    fn from(val: usize) -> LatencyMode {
+        match val {
+            1 => LatencyMode::Manual,
+            2 => LatencyMode::Auto,
+            _ => LatencyMode::Off,
+        }
+    }
+}
+
+impl fmt::Display for LatencyMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let printable = match *self {
+            LatencyMode::Off => "Off",
+            LatencyMode::Manual => "Manual",
+            LatencyMode::Auto => "Auto",
+        };
+        write!(f, "{}", printable)
+    }
+}
+
+#[derive(Default)]
+pub struct Latency {
+    mode: LatencyMode,
+    manual_ms: f32,
+    sample_rate: f64,
+    // round trip latency as reported by the host
+    host_samples: usize,
+    // the latency we compensate for
+    samples: usize,
+    // how many positions at the end of a recording or insert are still waiting for their (late) input
+    tail: usize,
+    tail_position: usize,
+}
+
+impl Latency {
+    pub fn set_mode(&mut self, mode: LatencyMode) {
+        self.mode = mode;
+        self.update();
+    }
+
+    pub fn set_manual_ms(&mut self, ms: f32) {
+        self.manual_ms = ms.max(0.);
+        self.update();
+    }
+
+    pub fn set_sample_rate(&mut self, fs: f64) {
+        self.sample_rate = fs;
+        self.update();
+    }
+
+    pub fn set_host_latency(&mut self, samples: usize) {
+        self.host_samples = samples;
+        self.update();
+    }
+
+    fn update(&mut self) {
+        self.samples = match self.mode {
+            LatencyMode::Off => 0,
+            LatencyMode::Manual => (self.sample_rate / 1000. * self.manual_ms as f64) as usize,
+            LatencyMode::Auto => self.host_samples,
+        };
+    }
+
+    /// the latency in samples
+    pub fn samples(&self) -> usize {
+        self.samples
+    }
+
+    /// convert *ms* to samples at the current sample rate
+    pub fn ms_to_samples(&self, ms: f32) -> usize {
+        (self.sample_rate / 1000. * ms as f64) as usize
+    }
+
+    /// where the sample arriving at *position* belongs in a loop of *length* samples
+    pub fn wrap(&self, position: usize, length: usize) -> usize {
+        if length == 0 {
+            return position;
+        }
+        (position + length - self.samples % length) % length
+    }
+
+    /// where a run of *count* samples arriving from *position* on belongs, as (skip, target):
+    /// the first *skip* samples were played before *start*, the others belong from *target* on
+    pub fn shift(&self, position: usize, count: usize, start: usize) -> (usize, usize) {
+        let late = start + self.samples;
+        let skip = late.saturating_sub(position).min(count);
+        (skip, (position + skip).saturating_sub(self.samples))
+    }
+
+    /// recording or inserting stopped at *position*, the input for the positions before it is still on its way
+    pub fn start_tail(&mut self, position: usize) {
+        self.tail = self.samples.min(position);
+        self.tail_position = position - self.tail;
+    }
+
+    /// is there late input still to be written?
+    pub fn in_tail(&self) -> bool {
+        self.tail > 0
+    }
+
+    /// how many late input samples are still to be written
+    pub fn tail_len(&self) -> usize {
+        self.tail
+    }
+
+    /// the position the next late input sample belongs to
+    pub fn next_tail(&mut self) -> Option<usize> {
+        if self.tail == 0 {
+            return None;
+        }
+        let position = self.tail_position;
+        self.tail -= 1;
+        self.tail_position += 1;
+        Some(position)
+    }
+
+    pub fn clear_tail(&mut self) {
+        self.tail = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn latency(samples: usize) -> Latency {
+        let mut l = Latency::default();
+        l.set_host_latency(samples);
+        l.set_mode(LatencyMode::Auto);
+        l
+    }
+
+    #[test]
+    fn test_modes() {
+        let mut l = Latency::default();
+        l.set_sample_rate(48000.);
+        l.set_manual_ms(10.);
+        l.set_host_latency(256);
+        assert_eq!(l.samples(), 0);
+        l.set_mode(LatencyMode::Manual);
+        assert_eq!(l.samples(), 480);
+        l.set_mode(LatencyMode::Auto);
+        assert_eq!(l.samples(), 256);
+    }
+
+    #[test]
+    fn test_wrap() {
+        let l = latency(10);
+        assert_eq!(l.wrap(15, 100), 5);
+        assert_eq!(l.wrap(5, 100), 95);
+        assert_eq!(l.wrap(5, 8), 3);
+        assert_eq!(l.wrap(5, 0), 5);
+    }
+
+    #[test]
+    fn test_shift() {
+        let l = latency(10);
+        assert_eq!(l.shift(5, 1, 0).0, 1);
+        assert_eq!(l.shift(10, 1, 0), (0, 0));
+        assert_eq!(l.shift(25, 1, 20).0, 1);
+        assert_eq!(l.shift(30, 1, 20), (0, 20));
+        // a run that starts before and ends after
+        assert_eq!(l.shift(5, 8, 0), (5, 0));
+    }
+
+    #[test]
+    fn test_tail() {
+        let mut l = latency(3);
+        l.start_tail(100);
+        assert!(l.in_tail());
+        assert_eq!(l.next_tail(), Some(97));
+        assert_eq!(l.next_tail(), Some(98));
+        assert_eq!(l.next_tail(), Some(99));
+        assert_eq!(l.next_tail(), None);
+        assert!(!l.in_tail());
+    }
+}
@@ -10,6 +10,7 @@ extern crate log_panics;
 extern crate simplelog;
 extern crate num_traits;
 extern crate asprim;
+extern crate rand;
 
 extern crate app_dirs;
 
@@ -18,15 +19,16 @@ use simplelog::*;
 use num_traits::Float;
 use asprim::AsPrim;
 
-use std::sync::RwLock;
-use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+use std::mem;
+use std::ptr;
 
 use app_dirs::*;
 
 use vst::plugin::{Info, Category, HostCallback, CanDo};
 use vst::buffer::{AudioBuffer, SendEventBuffer};
-use vst::host::Host;
-use vst::editor::Editor;
+use vst::host::{Host, OpCode};
+use vst::editor::{Editor, KeyCode};
 use vst::api::{self};
 use vst::event::MidiEvent;
 
@@ -36,7 +38,7 @@ use version::*;
 
 use easyvst::*;
 
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
 
 mod recording_buffer;
 
@@ -46,10 +48,102 @@ mod looper_fsm;
 
 use looper_fsm::*;
 
+mod smoother;
+
+use smoother::*;
+
+mod loop_window;
+
+use loop_window::*;
+
+mod stutter;
+
+use stutter::*;
+
+mod shuffle;
+
+use shuffle::*;
+
+mod routing;
+
+use routing::*;
+
+mod bindings;
+
+use bindings::*;
+
+mod settings;
+
+use settings::*;
+
+mod spsc;
+
+mod triple_buffer;
+
+mod osc;
+
+use osc::*;
+
+mod latency;
+
+use latency::*;
+
+mod resample;
+
+mod disk_store;
+
+use disk_store::*;
+
+mod tempo;
+
+use tempo::*;
+
+mod fixed_length;
+
+use fixed_length::*;
+
+mod scheduler;
+
+use scheduler::*;
+
+mod gesture;
+
+use gesture::*;
+
+mod controllers;
+
+use controllers::*;
+
+mod sync_bus;
+
+use sync_bus::*;
+
+mod metronome;
+
+use metronome::*;
+
+mod library;
+
+use library::*;
+
+mod block;
+
+use block::*;
+
+mod snapshot;
+
+use snapshot::*;
+
+mod transport;
+
+use transport::*;
+
 use tinyui::*;
 
 
 const APP_INFO: AppInfo = AppInfo { name: "PlexLooper", author: "Jens-Christian Fischer" };
+// the tempo in the host's time info is valid (kVstTempoValid)
+const TEMPO_VALID: i32 = 1 << 10;
 
 
 easyvst!(ParamId, ELState, ELPlugin);
@@ -60,13 +154,62 @@ easyvst!(ParamId, ELState, ELPlugin);
 pub enum ParamId {
     Feedback,
     Division,
+    DryLevel,
+    LoopLevel,
+    InputLevel,
+    Freeze,
+    StutterSlice,
+    Shuffle,
+    ShuffleSeed,
+    LoopChannels,
+    InputRouting,
+    // triggers for the looper functions, so they can be automated by the host
+    TrigRecord,
+    TrigPlay,
+    TrigStop,
+    TrigOverdub,
+    TrigMultiply,
+    TrigReplace,
+    TrigInsert,
+    TrigMute,
+    TrigUndo,
+    // the current state, only reported to the host
+    State,
+    Latency,
+    LatencyMode,
+    BeatsPerCycle,
+    BeatsPerBar,
+    MidiClock,
+    FixedMode,
+    FixedTempo,
+    FixedBars,
+    FixedSeconds,
+    AfterRecord,
+    InsertMode,
+    SyncRole,
+    Click,
+    ClickLevel,
+    CountIn,
+    HostTempo,
 }
 
-
-struct Command {
-    note: u8,
-    // the midi note this command is bound to
-    command: Commands,
+const NUM_TRIGGERS: usize = 9;
+
+/// the command a trigger parameter sends on its rising edge
+fn trigger_command(param_id: ParamId) -> Option<Commands> {
+    use ParamId::*;
+    match param_id {
+        TrigRecord => Some(Commands::Record),
+        TrigPlay => Some(Commands::Play),
+        TrigStop => Some(Commands::Stop),
+        TrigOverdub => Some(Commands::Overdub),
+        TrigMultiply => Some(Commands::MultiplyStart),
+        TrigReplace => Some(Commands::ReplaceStart),
+        TrigInsert => Some(Commands::InsertStart),
+        TrigMute => Some(Commands::Mute),
+        TrigUndo => Some(Commands::Undo),
+        _ => None,
+    }
 }
 
 
@@ -75,7 +218,18 @@ pub struct ELState {
     my_folder: PathBuf,
     // Parameters
     feedback: f32,
+    freeze: bool, // keep the loop intact during playback, ignoring feedback
     division: usize, // how many 8ths are we dividing into (1 - 16)
+    stutter_slice: usize, // which part of a subdivision is repeated when stuttering (1, 2, 4)
+    loop_channels: usize, // how many channels the next recording has
+    routing: Routing,
+    // which input is recorded into which loop channel
+    dry_level: Smoother,
+    // level of the live input passed through to the outputs
+    loop_level: Smoother,
+    // level of the loop playback
+    input_level: Smoother,
+    // level of the input going into the loop
 
     // buffers
 
@@ -88,12 +242,12 @@ pub struct ELState {
     division_len: usize,
     // which subdivision inside the cycle are we on
     subdivision: usize,
-    // when did we start a sync operation
-    sync_subdivision: usize,
-
-    sync_point: usize,
-    sync_window: usize,
-    // how many samples after a sync point is it still considered valid
+    scheduler: Scheduler,
+    // synced commands waiting for their sync point
+    clock: usize,
+    // samples since the plugin started, synced commands are scheduled by it
+    insert_started: usize,
+    // when (clock) the current insert started
     /// when inserting, we want to keep the sync_len constant as of the sync start
     /// otherwise, it would expand as new material is inserted
     insert_sync_len: usize,
@@ -105,10 +259,14 @@ pub struct ELState {
     write_position: usize,
     loop_length: usize,
     // length of current loop in samples
+    window: LoopWindow,
+    // the part of the loop we are playing when loop windowing
+    stutter: Stutter,
+    // the slice we are repeating when stuttering
+    shuffle: Shuffle,
+    // the order we play the subdivisions in
     // the playback position
-    seconds: String,
-    // display current position in seconds
-    sample_rate: RwLock<f64>,
+    sample_rate: f64,
 
     state: LooperState,
     prev_state: LooperState,
@@ -116,18 +274,740 @@ pub struct ELState {
     // a state change
     return_state: LooperState,
     events: Vec<MidiEvent>,
+    bindings: Vec<NoteBinding>,
+    // which midi note triggers which command
+    osc: Option<OscServer>,
+    triggers: [bool; NUM_TRIGGERS],
+    // which trigger parameters are currently on
+    pending_commands: Vec<Commands>,
+    // commands triggered by parameters, run at the start of the next block
+    reported_state: LooperState,
+    // the state we last reported to the host
+    undo: UndoSnapshot,
+    // the loop as it was before the last overdub, replace, insert, multiply or shuffle print
+    latency: Latency,
+    // compensation of the round trip latency for everything we record
+    insert_from: usize,
+    // where the current insert started
+    disk: Option<DiskStore>,
+    // the loop is kept on disk instead of in buffer (disk_loops setting)
+    tempo: Tempo,
+    // how many beats there are in a cycle and in a bar
+    midi_clock: bool,
+    // send midi clock following the loop
+    clock_running: bool,
+    // did we send a midi Start
+    clock_pulses: Vec<usize>,
+    clock_events: Vec<MidiEvent>,
+    fixed_length: FixedLength,
+    // how long a new recording is, when it isn't set by pressing Record again
+    record_target: Option<usize>,
+    // the length the current recording stops at
+    record_offset: usize,
+    // a recording that waited for the sync master starts this far into the block
+    sync_bus: SyncBus,
+    metronome: Metronome,
+    click_start: usize,
+    // the time the click counts beats from while there is no loop
+    counting_in: bool,
+    click_beat_len: f64,
+    // the beat length of the click while there is no loop, worked out once per block
+    host_tempo: bool,
+    // take the tempo of the click from the host, when there is no loop yet
+    host_bpm: Option<f64>,
+    insert_mode: InsertMode,
+    // what the Insert function does
+    reverse: bool,
+    half_speed: bool,
+    half_phase: usize,
+    // at half speed every frame is played twice, is this the second time
+    block: Block,
+    // the input, the playback and the gains of the current block
+    rehearse: RecordingBuffer,
+    // the pass that is recorded while rehearsing, it becomes the loop when the pass is done
+    gestures: Gestures,
+    // tap, long press and double tap on the bound notes
+    controllers: Vec<Controller>,
+    param_values: Vec<f32>,
+    // the parameters as they were last set, for the soft takeover of controllers
+    param_ranges: Vec<(f32, f32)>,
+    library: Option<Library>,
+    next_loop: Option<LoadedLoop>,
+    // a loop from the library, waiting for the end of the loop to take over
+    loop_name: String,
+    // the library loop that is playing
+    ui_link: Option<EngineLink>,
+    // snapshots for the UI, and its commands
 
 }
 
-unsafe impl Sync for ELState {}
+impl ELState {
+    /// the position in the loop *offset* samples into the current block, before it is reversed
+    fn position(&self, offset: usize) -> usize {
+        if self.window.enabled {
+            self.window.index(offset, self.loop_length)
+        } else if self.half_speed {
+            self.play_position + (self.half_phase + offset) / 2
+        } else {
+            self.play_position + offset
+        }
+    }
+
+    /// index into the recording buffer of the sample *offset* samples into the current block
+    fn play_index(&self, offset: usize) -> usize {
+        self.direction(self.position(offset))
+    }
+
+    /// does a pass through the loop (or the window) start *offset* samples into the current block,
+    /// reversed it starts at the end of the loop, at half speed only the first time its frame is played
+    fn pass_starts(&self, offset: usize) -> bool {
+        if self.loop_length == 0 || self.half_step(offset) {
+            return false;
+        }
+        let position = self.position(offset);
+        if self.window.enabled {
+            position == self.window.start
+        } else {
+            position.is_multiple_of(self.loop_length)
+        }
+    }
+
+    /// index into the recording buffer we write to *offset* samples into the current block
+    fn write_index(&self, offset: usize) -> usize {
+        let position = if self.window.enabled {
+            self.window.index(offset, self.loop_length)
+        } else if self.half_speed {
+            self.write_position + (self.half_phase + offset) / 2
+        } else {
+            self.write_position + offset
+        };
+        self.direction(self.latency.wrap(position, self.loop_length))
+    }
+
+    /// the index of *position* in the loop, running backwards when reversed
+    fn direction(&self, position: usize) -> usize {
+        if self.loop_length == 0 {
+            position
+        } else if self.reverse {
+            self.loop_length - 1 - position % self.loop_length
+        } else {
+            position % self.loop_length
+        }
+    }
+
+    /// the index of the frame played after *index*
+    fn next_index(&self, index: usize) -> usize {
+        if self.loop_length == 0 {
+            index + 1
+        } else if self.reverse {
+            (index + self.loop_length - 1) % self.loop_length
+        } else {
+            (index + 1) % self.loop_length
+        }
+    }
+
+    /// at half speed, is the frame *offset* samples into the block played for the second time
+    fn half_step(&self, offset: usize) -> bool {
+        self.half_speed && (self.half_phase + offset) % 2 == 1
+    }
+
+    /// is the loop written and played frame after frame, so runs of frames can be handled in one go
+    fn contiguous(&self) -> bool {
+        !self.reverse && !self.half_speed && !self.window.enabled
+    }
+
+    /// where the run of frames that starts *index* samples into a block of *samples* samples ends for recording:
+    /// at the next sync point, the end of the loop, the end of a fixed length recording or of the latency tail
+    fn record_run_end(&self, index: usize, samples: usize) -> usize {
+        let mut end = samples;
+        if let Some(at) = self.scheduler.next_time() {
+            end = end.min(at.saturating_sub(self.clock));
+        }
+        match self.state {
+            LooperState::Recording | LooperState::SyncStop(Commands::Record) => {
+                if let Some(target) = self.record_target {
+                    if target > self.loop_length {
+                        end = end.min(index + target - self.loop_length);
+                    }
+                }
+            }
+            _ => {
+                // the last late input sample is written together with what follows it
+                let tail = self.latency.tail_len();
+                if tail > 1 {
+                    end = end.min(index + tail - 1);
+                }
+                if self.contiguous() && self.loop_length > 0 {
+                    end = end.min(index + self.loop_length - self.write_index(index));
+                }
+            }
+        }
+        end.max(index + 1)
+    }
+
+    /// record the input of the run of frames that starts *index* samples into the block, returns where it ends
+    fn record_run(&mut self, index: usize, samples: usize, write_position: usize, channels: usize) -> usize {
+        let end = self.record_run_end(index, samples);
+        let count = end - index;
+
+        // input that belongs to the end of a recording or insert, it arrived after it was stopped
+        let recording = self.state == LooperState::Recording ||
+            self.state == LooperState::SyncStop(Commands::Record);
+        if !recording {
+            for offset in index..end {
+                let target = match self.latency.next_tail() {
+                    Some(target) => target,
+                    None => break,
+                };
+                let frame = &self.block.input[offset * channels..(offset + 1) * channels];
+                let record_buffer = loop_store(&mut self.buffer, &mut self.disk);
+                if target < record_buffer.length() {
+                    record_buffer.overwrite(target, frame);
+                } else {
+                    record_buffer.push(frame);
+                }
+            }
+        }
+        let in_tail = self.latency.in_tail();
+
+        match self.state {
+            LooperState::Recording | LooperState::SyncStop(Commands::Record) => {
+                // the input of the first samples was played before recording started
+                let position = (write_position + index).saturating_sub(self.record_offset);
+                let (skip, target) = self.latency.shift(position, count, 0);
+                let frames = &self.block.input[(index + skip) * channels..end * channels];
+                // what is already in the buffer is overwritten, the rest is added
+                let record_buffer = loop_store(&mut self.buffer, &mut self.disk);
+                let existing = record_buffer.length().saturating_sub(target).min(count - skip);
+                record_buffer.overwrite_frames(target, &frames[..existing * channels]);
+                record_buffer.push_frames(&frames[existing * channels..]);
+
+                self.loop_length += count;
+                self.cycle_len += count;
+
+                // a fixed length recording stops by itself, exactly at its length
+                if Some(self.loop_length) == self.record_target {
+                    self.prev_state = self.state;
+                    self.state = fixed_recording_stop(self);
+                    info!("fixed length reached: {}, new state: {}", self.loop_length, self.state);
+                }
+            }
+            LooperState::Inserting | LooperState::SyncStop(Commands::InsertStop) => {
+                let pos = write_position + index;
+                let record_buffer = loop_store(&mut self.buffer, &mut self.disk);
+                // the room made for the block counts, what is past the end doesn't
+                let inside = record_buffer.length().saturating_sub(pos).min(count);
+                self.loop_length += inside;
+                self.cycle_len += inside;
+                let (skip, target) = self.latency.shift(pos, count, self.insert_from);
+                let frames = &self.block.input[(index + skip) * channels..end * channels];
+                record_buffer.overwrite_frames(target, frames);
+            }
+            LooperState::Overdubbing if !in_tail => {
+                let feedback = self.feedback;
+                self.write_input(index, end, channels, Some(feedback));
+            }
+            LooperState::Replacing | LooperState::SyncStop(Commands::ReplaceStop) if !in_tail => {
+                self.write_input(index, end, channels, None);
+            }
+            _ => {}
+        }
+        end
+    }
+
+    /// overdub (with *feedback*) or replace the loop with the input from *start* to *end* samples into the block
+    fn write_input(&mut self, start: usize, end: usize, channels: usize, feedback: Option<f32>) {
+        if self.contiguous() {
+            let write_index = self.write_index(start);
+            let frames = &self.block.input[start * channels..end * channels];
+            let record_buffer = loop_store(&mut self.buffer, &mut self.disk);
+            match feedback {
+                Some(feedback) => record_buffer.overdub_frames(write_index, frames, feedback),
+                None => record_buffer.overwrite_frames(write_index, frames),
+            }
+            return;
+        }
+        for index in start..end {
+            // at half speed the loop is written every other sample
+            if self.half_step(index) {
+                continue;
+            }
+            let write_index = self.write_index(index);
+            let frame = &self.block.input[index * channels..(index + 1) * channels];
+            let record_buffer = loop_store(&mut self.buffer, &mut self.disk);
+            match feedback {
+                Some(feedback) => record_buffer.overdub(write_index, frame, feedback),
+                None => record_buffer.overwrite_frames(write_index, frame),
+            }
+        }
+    }
+
+    /// is the loop played frame after frame as it is, with *channels* channels
+    fn plays_contiguous(&self, channels: usize) -> bool {
+        self.contiguous() && !self.stutter.active && self.shuffle.mode == ShuffleMode::Off &&
+            self.state != LooperState::Rehearsing && self.loop_length > 0 &&
+            loop_store_channels(&self.buffer, &self.disk) == channels
+    }
+
+    /// play the run of frames that starts *index* samples into a block of *samples* samples into the block,
+    /// and do what the state does to the loop meanwhile. Returns where the run ends
+    ///
+    /// a run is a single frame when the loop isn't played as it is
+    fn play_run(&mut self, index: usize, samples: usize, channels: usize) -> usize {
+        // a loop from the library comes in at the start of the loop
+        let running = self.state == LooperState::Playing || self.state == LooperState::Muted;
+        if running && self.next_loop.is_some() && self.pass_starts(index) {
+            recall_loop(self, index);
+        }
+        // a new pass plays what was recorded in the last one
+        if self.state == LooperState::Rehearsing && self.pass_starts(index) {
+            mem::swap(&mut self.buffer, &mut self.rehearse);
+        }
+
+        let contiguous = self.plays_contiguous(channels);
+        let play_index = self.play_index(index);
+        let write_index = self.write_index(index);
+        let end = if self.state == LooperState::Stopped {
+            // nothing happens to a stopped loop
+            samples
+        } else if contiguous {
+            let end = samples.min(index + self.loop_length - play_index);
+            if self.state == LooperState::Substituting {
+                end.min(index + self.loop_length - write_index)
+            } else {
+                end
+            }
+        } else {
+            index + 1
+        };
+        let in_tail = self.latency.in_tail();
+        // when stuttering we hear the repeated slice, while the loop keeps running underneath
+        let hear_index = if self.stutter.active {
+            self.stutter.index(index)
+        } else {
+            self.shuffle.index(play_index, self.cycle_len, self.division_len)
+        };
+        // at half speed every other frame is halfway to the next one
+        let half_step = self.half_step(index);
+        let next_index = self.next_index(hear_index);
+        let play = &mut self.block.play[index * channels..end * channels];
+
+        match self.state {
+            LooperState::Muted | LooperState::Stopped => silence(play),
+            _ if contiguous => loop_store(&mut self.buffer, &mut self.disk).read_frames(play_index, play),
+            _ => {
+                let play_buffer = loop_store(&mut self.buffer, &mut self.disk);
+                silence(play);
+                if let Some(old) = play_buffer.get(hear_index) {
+                    for (sample, sample_old) in play.iter_mut().zip(old) {
+                        *sample = *sample_old;
+                    }
+                }
+                if half_step {
+                    if let Some(next) = play_buffer.get(next_index) {
+                        for (sample, sample_next) in play.iter_mut().zip(next) {
+                            *sample = (*sample + *sample_next) * 0.5;
+                        }
+                    }
+                }
+            }
+        }
+
+        // the old loop has been heard, now it can be replaced
+        if self.state == LooperState::Substituting && !in_tail && !half_step {
+            let frames = &self.block.input[index * channels..end * channels];
+            loop_store(&mut self.buffer, &mut self.disk).overwrite_frames(write_index, frames);
+        }
+
+        if self.state == LooperState::Rehearsing && !in_tail {
+            let frame = &self.block.input[index * channels..end * channels];
+            if write_index > play_index {
+                // the input is late by the latency, the end of the last pass is still coming in
+                self.buffer.overwrite(write_index, frame);
+            } else {
+                self.rehearse.overwrite(write_index, frame);
+            }
+        }
+
+        // like on a tape loop, feedback reduces the recorded signal on every pass
+        if self.state == LooperState::Playing && !self.freeze {
+            loop_store(&mut self.buffer, &mut self.disk).decay_frames(play_index, end - index, self.feedback);
+        }
+
+        for offset in index..end {
+            self.block.clicks[offset] = self.click(offset);
+        }
+        end
+    }
+
+    /// the tempo of the loop in beats per minute, 0 while there is no loop
+    pub fn bpm(&self) -> f64 {
+        self.tempo.bpm(self.cycle_len, self.sample_rate)
+    }
+
+    /// the length of the loop in bars
+    pub fn bars(&self) -> f64 {
+        self.tempo.bars(self.loop_length, self.cycle_len)
+    }
+
+    /// the play position as bar.beat.tick
+    pub fn bar_beat_tick(&self) -> BarBeatTick {
+        self.tempo.position(self.play_position, self.cycle_len)
+    }
+
+    /// the state after a block, for the UI
+    fn snapshot(&self) -> Snapshot {
+        let sample_rate = self.sample_rate;
+        let mut snapshot = Snapshot::default();
+        snapshot.state = self.state;
+        snapshot.play_position = self.play_position;
+        snapshot.loop_length = self.loop_length;
+        snapshot.cycle_len = self.cycle_len;
+        snapshot.position = self.bar_beat_tick();
+        snapshot.seconds = if sample_rate > 0. { self.play_position as f64 / sample_rate } else { 0. };
+        snapshot.cycles = self.cycles;
+        snapshot.total_cycles = self.total_cycles;
+        snapshot.division = self.division;
+        snapshot.bars = self.bars();
+        snapshot.bpm = self.bpm();
+        snapshot.insert_mode = self.insert_mode;
+        snapshot.reverse = self.reverse;
+        snapshot.half_speed = self.half_speed;
+        snapshot.sync_role = self.sync_bus.role();
+        snapshot.set_loop_name(&self.loop_name);
+        snapshot
+    }
+
+    /// move the play and write positions on after a block of *samples* samples
+    fn advance(&mut self, samples: usize) {
+        // at half speed the loop moves on half as far
+        let advance = if self.half_speed { (self.half_phase + samples) / 2 } else { samples };
+        self.half_phase = (self.half_phase + samples) % 2;
+
+        match self.state {
+            // update the write position
+            LooperState::Recording | LooperState::Inserting | LooperState::Overdubbing |
+            LooperState::Replacing | LooperState::SyncStart(_) | LooperState::SyncStop(_) |
+            LooperState::Substituting | LooperState::Rehearsing => {
+                self.write_position += advance - self.record_offset.min(advance);
+                // still empty while waiting for the count-in or the sync master
+                self.write_position = if self.loop_length > 0 {
+                    self.write_position % self.loop_length
+                } else { 0 };
+            }
+            _ => {}
+        }
+
+        self.clock += samples;
+        self.record_offset = 0;
+
+        if self.stutter.active {
+            self.stutter.advance(samples);
+        }
+
+        if self.state != LooperState::Stopped && self.window.enabled {
+            // play and write positions follow the window
+            self.window.advance(samples);
+            self.play_position = self.window.index(0, self.loop_length);
+            self.write_position = self.play_position;
+        } else if self.state != LooperState::Stopped {
+            self.play_position += advance;
+            self.play_position = if self.loop_length > 0 {
+                self.play_position % self.loop_length
+            } else { 0 };
+        }
+
+        if self.state != LooperState::Stopped {
+            self.division_len = (self.cycle_len / self.division) as usize;
+            self.subdivision = if self.division_len > 0 {
+                (self.play_position / self.division_len) as usize
+            } else { 0 }
+        }
+    }
+
+    /// send midi clock (and start/stop) for a block of *samples* samples
+    fn send_clock(&mut self, samples: usize) -> bool {
+        let running = self.midi_clock && self.cycle_len > 0 &&
+            !matches!(self.state, LooperState::Stopped | LooperState::Recording | LooperState::Clearing);
+        self.clock_events.clear();
+        if running != self.clock_running {
+            self.clock_running = running;
+            self.clock_events.push(midi_event([if running { 0xFA } else { 0xFC }, 0, 0], 0));
+        }
+        if running {
+            self.tempo.clock_pulses(self.play_position, samples, self.loop_length, self.cycle_len,
+                                    &mut self.clock_pulses);
+            for pulse in self.clock_pulses.iter() {
+                self.clock_events.push(midi_event([0xF8, 0, 0], *pulse));
+            }
+        }
+        if self.clock_events.is_empty() {
+            return false;
+        }
+        self.send_buffer.store_midi(&self.clock_events);
+        true
+    }
+
+    /// samples from loop position *position* to the next subdivision (or the end of the loop)
+    fn sync_distance(&self, position: usize) -> usize {
+        if self.division_len == 0 || self.loop_length == 0 {
+            return 0;
+        }
+        let position = position % self.loop_length;
+        (self.division_len - position % self.division_len).min(self.loop_length - position)
+    }
+
+    /// the time (clock) of the first sync point after time *at*
+    fn next_sync(&self, at: usize) -> usize {
+        let position = self.play_position + at.saturating_sub(self.clock);
+        at + self.sync_distance(position)
+    }
+
+    /// the length of a beat of the click while there is no loop, from the host or the Fixed Tempo
+    fn free_beat_len(&self) -> f64 {
+        let bpm = match self.host_bpm {
+            Some(bpm) if self.host_tempo && bpm > 0. => bpm,
+            _ => self.fixed_length.bpm as f64,
+        };
+        if bpm <= 0. {
+            return 0.;
+        }
+        60. * self.sample_rate / bpm
+    }
+
+    /// the length of the count-in in samples, 0 without one
+    pub fn count_in_len(&self) -> usize {
+        let beats = self.metronome.count_in * self.tempo.beats_per_bar.max(1);
+        (beats as f64 * self.free_beat_len()).round() as usize
+    }
+
+    /// the click *index* samples into the block
+    fn click(&mut self, index: usize) -> f32 {
+        let counting_in = self.counting_in && self.state == LooperState::SyncStart(Commands::Record);
+        if self.metronome.mode == ClickMode::Off && !counting_in {
+            return 0.;
+        }
+        let recording = self.state == LooperState::Recording || self.state == LooperState::SyncStop(Commands::Record);
+        // a count-in over a loop counts the beats of its own length, not the loop's
+        let beat = if !counting_in && self.cycle_len > 0 && self.loop_length > 0 && !recording &&
+            self.state != LooperState::Stopped {
+            // the beats of the loop
+            beat_at((self.play_position + index) % self.loop_length, self.tempo.beat_len(self.cycle_len))
+        } else {
+            beat_at((self.clock + index).saturating_sub(self.click_start), self.click_beat_len)
+        };
+        if let Some(beat) = beat {
+            self.metronome.trigger(beat % self.tempo.beats_per_bar.max(1) == 0);
+        }
+        self.metronome.next()
+    }
+
+    /// how long an insert started now is (or a multiple of), a subdivision or the sync master's cycle
+    fn insert_unit(&self) -> usize {
+        self.sync_bus.cycle_len().unwrap_or(self.division_len)
+    }
+
+    /// a synced *command* is due *index* samples into a block of *samples* samples
+    fn sync_reached(&mut self, command: Commands, index: usize, samples: usize) {
+        let pos = self.write_position + index;
+        info!("sync point reached: {} at {}", command, pos);
+        self.state = match (self.state, command) {
+            (LooperState::SyncStart(Commands::ReplaceStart), Commands::ReplaceStart) => {
+                if self.scheduler.is_pending(Commands::ReplaceStop) {
+                    LooperState::SyncStop(Commands::ReplaceStop)
+                } else {
+                    LooperState::Replacing
+                }
+            }
+            (LooperState::Replacing, Commands::ReplaceStop) |
+            (LooperState::SyncStop(Commands::ReplaceStop), Commands::ReplaceStop) => self.return_state,
+            (LooperState::SyncStart(Commands::Record), Commands::Record) => {
+                // the recording starts in the middle of the block
+                self.record_offset = index;
+                master_sync_reached(self, command)
+            }
+            (LooperState::SyncStop(Commands::Record), Commands::Record) |
+            (LooperState::SyncStart(Commands::MultiplyStart), Commands::MultiplyStart) |
+            (LooperState::Multiplying, Commands::MultiplyStop) => master_sync_reached(self, command),
+            (LooperState::SyncStart(Commands::InsertStart), Commands::InsertStart) => {
+                self.insert_sync_len = self.insert_unit(); // store the current length of a subdivision
+                self.insert_from = pos;
+                self.insert_started = self.clock + index;
+                loop_store(&mut self.buffer, &mut self.disk).insert_empty(pos, samples - index);
+                if self.scheduler.is_pending(Commands::InsertStop) {
+                    LooperState::SyncStop(Commands::InsertStop)
+                } else {
+                    LooperState::Inserting
+                }
+            }
+            (LooperState::Inserting, Commands::InsertStop) |
+            (LooperState::SyncStop(Commands::InsertStop), Commands::InsertStop) => {
+                // the rest of the block was already made room for
+                loop_store(&mut self.buffer, &mut self.disk).remove(pos, samples - index);
+                self.latency.start_tail(pos);
+                self.return_state
+            }
+            (state, _) => state,
+        };
+    }
+
+    /// *note* went on with *velocity* at time *at*
+    fn note_on(&mut self, note: u8, velocity: u8, at: usize) {
+        let gesture = self.gestures.press(note, at);
+        let double = if gesture == Gesture::DoubleTap {
+            find_binding(&self.bindings, note, Gesture::DoubleTap)
+        } else {
+            None
+        };
+        // a double tap without a binding of its own is another tap, a tap fires right away even when
+        // the note has a long press, that comes on top of it
+        let binding = double.or_else(|| find_binding(&self.bindings, note, Gesture::Tap));
+        if let Some(binding) = binding {
+            self.fire(&binding, velocity);
+        }
+    }
+
+    /// *note* went off, a held command is released
+    fn note_off(&mut self, note: u8) {
+        self.gestures.release(note);
+        if let Some(binding) = find_binding(&self.bindings, note, Gesture::Tap) {
+            if let Some(command) = binding.command.release() {
+                self.state = looper_cycle(self, command);
+            }
+        }
+    }
+
+    /// send the command of *binding* played with *velocity*
+    fn fire(&mut self, binding: &NoteBinding, velocity: u8) {
+        let level = binding.curve.apply(velocity);
+        match binding.velocity {
+            VelocityTarget::InputLevel => self.input_level.set_target(level),
+            VelocityTarget::LoopLevel => self.loop_level.set_target(level),
+            // a command without velocity goes back to the levels of the host
+            VelocityTarget::Off => {
+                self.input_level.set_target(self.host_level(ParamId::InputLevel));
+                self.loop_level.set_target(self.host_level(ParamId::LoopLevel));
+            }
+        }
+        self.prev_state = self.state;
+        self.state = looper_cycle(self, binding.command);
+    }
+
+    /// the level the host set with *param*, full level before it set any
+    fn host_level(&self, param: ParamId) -> f32 {
+        self.param_values.get(param as usize).cloned().unwrap_or(1.0)
+    }
+
+    /// the folder disk loops are kept in
+    fn disk_folder(&self) -> PathBuf {
+        self.my_folder.join("loops")
+    }
+
+    /// convert the loop from *from* to *to* Hz, so it keeps its pitch and duration
+    fn resample_loop(&mut self, from: f64, to: f64) {
+        if self.disk.is_some() {
+            info!("disk loops are not resampled");
+            return;
+        }
+        if self.loop_length > 0 {
+            info!("resampling loop of {} samples from {} to {} Hz", self.loop_length, from, to);
+            self.buffer = resample::resample(&self.buffer, self.loop_length, from, to);
+        }
+        let scale = |value: usize| resample::scale(value, from, to);
+        self.loop_length = scale(self.loop_length);
+        self.cycle_len = scale(self.cycle_len);
+        self.division_len = scale(self.division_len);
+        self.insert_sync_len = scale(self.insert_sync_len);
+        self.insert_from = scale(self.insert_from);
+        self.scheduler.rescale(self.clock, from, to);
+        self.window.start = scale(self.window.start);
+        self.window.length = scale(self.window.length);
+        self.window.offset = scale(self.window.offset);
+        if self.loop_length > 0 {
+            self.play_position = scale(self.play_position) % self.loop_length;
+            self.write_position = scale(self.write_position) % self.loop_length;
+        }
+        self.stutter.stop();
+        self.latency.clear_tail();
+        self.undo.resample(from, to);
+    }
+}
 
 impl UserState<ParamId> for ELState {
     fn param_changed(&mut self, _host: &mut HostCallback, param_id: ParamId, val: f32) {
         info!("param_changed {:?} {:2}", param_id, val);
+        // OSC and controllers can send anything, keep it in the parameter's range
+        if val.is_nan() {
+            return;
+        }
+        let val = match self.param_ranges.get(param_id as usize) {
+            Some(&(min, max)) => val.clamp(min, max),
+            None => val,
+        };
+        if let Some(value) = self.param_values.get_mut(param_id as usize) {
+            *value = val;
+        }
         use ParamId::*;
         match param_id {
             Feedback => self.feedback = val,
-            Division => self.division = val as usize,
+            Division => {
+                self.division = val as usize;
+                self.shuffle.generate(self.division);
+            }
+            DryLevel => self.dry_level.set_target(val),
+            LoopLevel => self.loop_level.set_target(val),
+            InputLevel => self.input_level.set_target(val),
+            Freeze => self.freeze = val > 0.5,
+            StutterSlice => self.stutter_slice = 1 << (val as usize),
+            Shuffle => self.shuffle.set_mode(ShuffleMode::from(val as usize), self.division),
+            ShuffleSeed => self.shuffle.set_seed(val as usize, self.division),
+            LoopChannels => self.loop_channels = val as usize,
+            InputRouting => {
+                let routing = &self.routing;
+                self.routing = Routing::new(RoutingMode::from(val as usize), routing.inputs(), routing.channels());
+            }
+            TrigRecord | TrigPlay | TrigStop | TrigOverdub | TrigMultiply | TrigReplace | TrigInsert |
+            TrigMute | TrigUndo => {
+                // fire on the rising edge, release held commands on the falling edge
+                let trigger = param_id as usize - TrigRecord as usize;
+                let on = val > 0.5;
+                if on != self.triggers[trigger] {
+                    self.triggers[trigger] = on;
+                    if let Some(command) = trigger_command(param_id) {
+                        if on {
+                            self.pending_commands.push(command);
+                        } else if let Some(release) = command.release() {
+                            self.pending_commands.push(release);
+                        }
+                    }
+                }
+            }
+            // read only
+            State => {}
+            Latency => self.latency.set_manual_ms(val),
+            LatencyMode => self.latency.set_mode(latency::LatencyMode::from(val as usize)),
+            BeatsPerCycle => self.tempo.beats_per_cycle = val as usize,
+            BeatsPerBar => self.tempo.beats_per_bar = val as usize,
+            MidiClock => self.midi_clock = val > 0.5,
+            FixedMode => self.fixed_length.mode = fixed_length::FixedMode::from(val as usize),
+            InsertMode => {
+                let mode = looper_fsm::InsertMode::from(val as usize);
+                if mode != self.insert_mode {
+                    // a new mode starts from normal playback
+                    self.reverse = false;
+                    self.half_speed = false;
+                    self.insert_mode = mode;
+                }
+            }
+            FixedTempo => self.fixed_length.bpm = val,
+            FixedBars => self.fixed_length.bars = val as usize,
+            FixedSeconds => self.fixed_length.seconds = val,
+            AfterRecord => self.fixed_length.after = fixed_length::AfterRecord::from(val as usize),
+            SyncRole => self.sync_bus.set_role(sync_bus::SyncRole::from(val as usize)),
+            Click => self.metronome.mode = ClickMode::from(val as usize),
+            ClickLevel => self.metronome.level = val,
+            CountIn => self.metronome.count_in = val as usize,
+            HostTempo => self.host_tempo = val > 0.5,
         }
     }
 
@@ -137,6 +1017,31 @@ impl UserState<ParamId> for ELState {
         match param_id {
             Feedback => format!("{:.2} ", val),
             Division => format!("{}", val),
+            DryLevel | LoopLevel | InputLevel => format!("{:.2}", val),
+            Freeze => if val > 0.5 { "On".to_string() } else { "Off".to_string() },
+            StutterSlice => format!("1/{}", 1 << (val as usize)),
+            Shuffle => format!("{}", ShuffleMode::from(val as usize)),
+            ShuffleSeed => format!("{}", val as usize),
+            LoopChannels => format!("{}", val as usize),
+            InputRouting => format!("{}", RoutingMode::from(val as usize)),
+            TrigRecord | TrigPlay | TrigStop | TrigOverdub | TrigMultiply | TrigReplace | TrigInsert |
+            TrigMute | TrigUndo => if val > 0.5 { "On".to_string() } else { "Off".to_string() },
+            State => format!("{}", LooperState::from_index(val.round() as usize)),
+            Latency => format!("{:.1} ms ({} samples)", val, self.latency.ms_to_samples(val)),
+            LatencyMode => format!("{}", latency::LatencyMode::from(val as usize)),
+            BeatsPerCycle | BeatsPerBar => format!("{}", val as usize),
+            MidiClock => if val > 0.5 { "On".to_string() } else { "Off".to_string() },
+            FixedMode => format!("{}", fixed_length::FixedMode::from(val as usize)),
+            InsertMode => format!("{}", looper_fsm::InsertMode::from(val as usize)),
+            FixedTempo => format!("{:.1} BPM", val),
+            FixedBars => format!("{}", val as usize),
+            FixedSeconds => format!("{:.1} s", val),
+            AfterRecord => format!("{}", fixed_length::AfterRecord::from(val as usize)),
+            SyncRole => format!("{}", sync_bus::SyncRole::from(val as usize)),
+            Click => format!("{}", ClickMode::from(val as usize)),
+            ClickLevel => format!("{:.2}", val),
+            CountIn => format!("{} bars", val as usize),
+            HostTempo => if val > 0.5 { "On".to_string() } else { "Off".to_string() },
         }
     }
 }
@@ -147,7 +1052,12 @@ type ELPluginState = PluginState<ParamId, ELState>;
 struct ELPlugin {
     state: ELPluginState,
     ui: Option<UiState>,
-
+    ui_link: Option<UiLink>,
+    // the UI only talks to the engine through the link
+    loop_list: Option<ui::SharedLoopList>,
+    transport: Option<Arc<Mutex<TransportButtons>>>,
+    keys: Keys,
+    // the shortcut keys that are down
 }
 
 impl ELPlugin {}
@@ -157,6 +1067,41 @@ impl EasyVst<ParamId, ELState> for ELPlugin {
         vec![
             ParamDef::new("Feedback", 0.0, 1.0, 1.0),
             ParamDef::new("Division", 1.0, 16.0, 8.0),
+            ParamDef::new("Dry Level", 0.0, 1.0, 1.0),
+            ParamDef::new("Loop Level", 0.0, 1.0, 1.0),
+            ParamDef::new("Input Level", 0.0, 1.0, 1.0),
+            ParamDef::new("Freeze", 0.0, 1.0, 0.0),
+            ParamDef::new("Stutter Slice", 0.0, 2.0, 0.0),
+            ParamDef::new("Shuffle", 0.0, 3.0, 0.0),
+            ParamDef::new("Shuffle Seed", 0.0, 1000.0, 0.0),
+            ParamDef::new("Loop Channels", 1.0, MAX_CHANNELS as f32, 2.0),
+            ParamDef::new("Input Routing", 0.0, 2.0, 0.0),
+            ParamDef::new("Trig Record", 0.0, 1.0, 0.0),
+            ParamDef::new("Trig Play", 0.0, 1.0, 0.0),
+            ParamDef::new("Trig Stop", 0.0, 1.0, 0.0),
+            ParamDef::new("Trig Overdub", 0.0, 1.0, 0.0),
+            ParamDef::new("Trig Multiply", 0.0, 1.0, 0.0),
+            ParamDef::new("Trig Replace", 0.0, 1.0, 0.0),
+            ParamDef::new("Trig Insert", 0.0, 1.0, 0.0),
+            ParamDef::new("Trig Mute", 0.0, 1.0, 0.0),
+            ParamDef::new("Trig Undo", 0.0, 1.0, 0.0),
+            ParamDef::new("State", 0.0, (STATE_COUNT - 1) as f32, 0.0),
+            ParamDef::new("Latency", 0.0, 100.0, 0.0),
+            ParamDef::new("Latency Mode", 0.0, 2.0, 0.0),
+            ParamDef::new("Beats per Cycle", 1.0, 32.0, 4.0),
+            ParamDef::new("Beats per Bar", 1.0, 16.0, 4.0),
+            ParamDef::new("MIDI Clock", 0.0, 1.0, 0.0),
+            ParamDef::new("Fixed Length", 0.0, 2.0, 0.0),
+            ParamDef::new("Fixed Tempo", 40.0, 240.0, 120.0),
+            ParamDef::new("Fixed Bars", 1.0, 64.0, 4.0),
+            ParamDef::new("Fixed Seconds", 0.5, 120.0, 10.0),
+            ParamDef::new("After Record", 0.0, 2.0, 0.0),
+            ParamDef::new("Insert Mode", 0.0, 4.0, 0.0),
+            ParamDef::new("Sync Role", 0.0, 2.0, 0.0),
+            ParamDef::new("Click", 0.0, 2.0, 0.0),
+            ParamDef::new("Click Level", 0.0, 1.0, 0.5),
+            ParamDef::new("Count In", 0.0, 8.0, 0.0),
+            ParamDef::new("Host Tempo", 0.0, 1.0, 0.0),
         ]
     }
 
@@ -171,9 +1116,9 @@ impl EasyVst<ParamId, ELState> for ELPlugin {
             unique_id: 0x87a93b3,
             category: Category::Effect,
             version: Version::get_version(),
-            inputs: 2,
-            outputs: 2,
-            parameters: 2,
+            inputs: MAX_CHANNELS as i32,
+            outputs: MAX_CHANNELS as i32,
+            parameters: ELPlugin::params().len() as i32,
 
             ..Info::default()
         }
@@ -221,10 +1166,72 @@ impl EasyVst<ParamId, ELState> for ELPlugin {
         state.division_len = 0;
         state.subdivision = 0;
 
-        state.sync_window = 1;
+        state.stutter_slice = 1;
+        state.loop_channels = 2;
+
+        state.dry_level = Smoother::new(1.0);
+        state.loop_level = Smoother::new(1.0);
+        state.input_level = Smoother::new(1.0);
 
         state.total_cycles = 1;
         state.events = Vec::with_capacity(1024);
+        state.pending_commands = Vec::with_capacity(64);
+        state.tempo = Tempo::default();
+        state.fixed_length = FixedLength::default();
+        state.clock_pulses = Vec::with_capacity(1024);
+        state.clock_events = Vec::with_capacity(1024);
+        state.send_buffer = SendEventBuffer::new(1024);
+        state.bindings = load_bindings(&state.my_folder.join("bindings.txt"))
+            .unwrap_or_else(default_bindings);
+        info!("bindings: {:?}", state.bindings);
+        let params = ELPlugin::params();
+        state.controllers = load_controllers(&state.my_folder.join("controllers.txt"), &params);
+        info!("controllers: {:?}", state.controllers);
+        state.param_values = params.iter().map(|p| p.default).collect();
+        state.param_ranges = params.iter().map(|p| (p.min, p.max)).collect();
+        // the host may never send these, a feedback of 0 would erase the loop after one pass
+        state.feedback = state.param_values[ParamId::Feedback as usize];
+        state.division = state.param_values[ParamId::Division as usize] as usize;
+
+        let settings = Settings::load(&state.my_folder.join("plexlooper.conf"));
+        state.gestures = Gestures::new(settings.long_press, settings.double_tap);
+        if settings.disk_loops {
+            state.disk = match DiskStore::create(&state.disk_folder(), state.loop_channels) {
+                Ok(disk) => Some(disk),
+                Err(e) => {
+                    info!("can't keep loops on disk: {}", e);
+                    None
+                }
+            };
+        }
+        state.library = match Library::start(&state.my_folder.join("library")) {
+            Ok(library) => Some(library),
+            Err(e) => {
+                info!("can't open the loop library: {}", e);
+                None
+            }
+        };
+        self.loop_list = state.library.as_ref().map(|library| library.remote() as ui::SharedLoopList);
+        let (engine_link, ui_link) = snapshot::link();
+        state.ui_link = Some(engine_link);
+        self.transport = Some(Arc::new(Mutex::new(TransportButtons {
+            sender: ui_link.sender(),
+            toggles: Toggles::default(),
+        })));
+        self.ui_link = Some(ui_link);
+        if settings.osc_port > 0 {
+            let param_names = ELPlugin::params().iter().map(|p| p.name.to_string()).collect();
+            state.osc = match OscServer::start(settings.osc_address, settings.osc_port, param_names) {
+                Ok(server) => {
+                    info!("OSC server listening on {}", server.local_addr());
+                    Some(server)
+                }
+                Err(e) => {
+                    info!("can't start OSC server on port {}: {}", settings.osc_port, e);
+                    None
+                }
+            };
+        }
         info!("Init Done");
     }
 
@@ -233,119 +1240,164 @@ impl EasyVst<ParamId, ELState> for ELPlugin {
     }
 
     fn set_sample_rate(&mut self, fs: f32) {
-        const SYNC_DELAY: f64 = 40.; // how many ms are we allowing a sync to happen
         info!("set_sample_rate: {}", fs);
         let fs = fs as f64;
         let state = &mut self.state.user_state;
-        *state.sample_rate.write().unwrap().deref_mut() = fs;
-        state.sync_window = (fs / 1000. * SYNC_DELAY) as usize;
+        let old_fs = state.sample_rate;
+        state.sample_rate = fs;
+        if old_fs > 0. && old_fs != fs {
+            state.resample_loop(old_fs, fs);
+        }
+        state.dry_level.set_sample_rate(fs);
+        state.loop_level.set_sample_rate(fs);
+        state.input_level.set_sample_rate(fs);
+        state.latency.set_sample_rate(fs);
+        state.metronome.set_sample_rate(fs);
+        if let Some(ref library) = state.library {
+            library.set_sample_rate(fs);
+        }
+        let block_size = self.state.host.get_block_size().max(0) as usize;
+        self.state.user_state.block.reserve(block_size);
+        self.update_host_latency(block_size);
+    }
+
+    fn set_block_size(&mut self, size: i64) {
+        info!("set_block_size: {}", size);
+        let block_size = size.max(0) as usize;
+        self.state.user_state.block.reserve(block_size);
+        self.update_host_latency(block_size);
     }
 
     fn process<T: Float + AsPrim>(&mut self, events: &api::Events, buffer: &mut AudioBuffer<T>) {
         let state = &mut self.state.user_state;
 
+        let samples = buffer.samples();
         let (inputs, mut outputs) = buffer.split();
 
-        // Assume 2 channels
-        if inputs.len() < 2 || outputs.len() < 2 {
+        let num_inputs = inputs.len().min(MAX_CHANNELS);
+        let num_outputs = outputs.len().min(MAX_CHANNELS);
+        if num_outputs == 0 {
             return;
         }
 
-        // Iterate over inputs as (&f32, &f32)
-        let (l, r) = inputs.split_at(1);
-        let stereo_in = l[0].iter().zip(r[0].iter());
-
-        // Iterate over outputs as (&mut f32, &mut f32)
-        let (mut l, mut r) = outputs.split_at_mut(1);
-        let stereo_out = l[0].iter_mut().zip(r[0].iter_mut());
-
-
-        let stereo_in_len = stereo_in.len();
-        let stereo_out_len = stereo_out.len();
-        let play_position = state.play_position;
         let write_position = state.write_position;
 
         // info!("write pos/reading pos {}/{}", write_position, play_position);
 
-        state.sync_point = state.subdivision * state.division_len;
-
         // if we are inserting, we need to shift all exisisting samples to the right
         // in order to save time, we will insert a new vec with the size of the DAW buffer
 
         match state.state {
             LooperState::Inserting | LooperState::SyncStop(Commands::InsertStop) => {
-                let record_buffer = &mut state.buffer;
-                record_buffer.insert_empty(write_position, stereo_in.len());
-                // info!("extended buffer at {} : {}, new len {}", write_position, stereo_in.len(), record_buffer.length());
+                let record_buffer = loop_store(&mut state.buffer, &mut state.disk);
+                record_buffer.insert_empty(write_position, samples);
+                // info!("extended buffer at {} : {}, new len {}", write_position, samples, record_buffer.length());
             }
             _ => {}
         }
 
+        if state.host_tempo {
+            state.host_bpm = self.state.host.get_time_info(TEMPO_VALID)
+                .and_then(|info| if info.flags & TEMPO_VALID != 0 { Some(info.tempo) } else { None });
+        }
+        state.click_beat_len = state.free_beat_len();
+
+        // where the sync master is, before commands wait for it
+        state.sync_bus.follow(state.clock, samples);
+
+        // commands and parameter changes from OSC
+        if let Some(osc) = state.osc.take() {
+            while let Some(input) = osc.receive() {
+                match input {
+                    OscInput::Command(command) => {
+                        state.prev_state = state.state;
+                        state.state = looper_cycle(state, command);
+                        info!("OSC command {}, new state: {}", command, state.state);
+                    }
+                    OscInput::Param(index, val) => {
+                        state.param_changed(&mut self.state.host, ParamId::from(index), val);
+                    }
+                }
+            }
+            state.osc = Some(osc);
+        }
+
+        // commands and parameter changes from the UI
+        if let Some(link) = state.ui_link.take() {
+            while let Some(input) = link.receive() {
+                match input {
+                    UiInput::Command(command) => {
+                        state.prev_state = state.state;
+                        state.state = looper_cycle(state, command);
+                        info!("UI command {}, new state: {}", command, state.state);
+                    }
+                    UiInput::Param(index, val) => {
+                        state.param_changed(&mut self.state.host, ParamId::from(index), val);
+                        let val = state.param_values[index];
+                        let (min, max) = state.param_ranges[index];
+                        let normalized = if max > min { (val - min) / (max - min) } else { 0. };
+                        self.state.host.automate(index as i32, normalized);
+                    }
+                }
+            }
+            state.ui_link = Some(link);
+        }
+
+        // commands from trigger parameters
+        let mut pending_commands = mem::take(&mut state.pending_commands);
+        for command in pending_commands.drain(..) {
+            state.prev_state = state.state;
+            state.state = looper_cycle(state, command);
+            info!("trigger {}, new state: {}", command, state.state);
+        }
+        state.pending_commands = pending_commands;
+
         use vst::event::Event;
 
 
         for e in events.events() {
-            const A3_PITCH: u8 = 69;  // Record
-            const G3_PITCH: u8 = 67;  // Stop
-            const F3_PITCH: u8 = 65;  // Play
-            const E3_PITCH: u8 = 64;  // Overdub
-            const D3_PITCH: u8 = 62;  // Replace
-            const C3_PITCH: u8 = 60;  // Mute
-            const B2_PITCH: u8 = 59;  // Insert
-            const A2_PITCH: u8 = 57;  // Multiply
             match e {
                 Event::Midi(mut ev) => {
                     let midi_event = status(ev.data[0]);
                     info!("Midi Event: {:?}", midi_event);
 
                     match midi_event {
-                        Status::NoteOn => {
+                        // a NoteOn with velocity 0 is a NoteOff
+                        Status::NoteOn if ev.data[2] > 0 => {
                             let pitch = ev.data[1];
-                            info!("Pitch: {}", pitch);
-                            state.prev_state = state.state;
-                            match pitch {
-                                A3_PITCH => {
-                                    state.state = looper_cycle(state, Commands::Record);
-                                }
-                                G3_PITCH => {
-                                    state.state = looper_cycle(state, Commands::Stop);
-                                }
-                                F3_PITCH => {
-                                    state.state = looper_cycle(state, Commands::Play);
-                                }
-                                E3_PITCH => {
-                                    state.state = looper_cycle(state, Commands::Overdub);
-                                }
-                                D3_PITCH => {
-                                    state.state = looper_cycle(state, Commands::ReplaceStart);
-                                }
-                                C3_PITCH => {
-                                    state.state = looper_cycle(state, Commands::Mute);
-                                }
-                                B2_PITCH => {
-                                    state.state = looper_cycle(state, Commands::InsertStart);
-                                }
-                                A2_PITCH => {
-                                    state.state = looper_cycle(state, Commands::MultiplyStart);
+                            let velocity = ev.data[2];
+                            info!("Pitch: {} Velocity: {}", pitch, velocity);
+                            let at = state.clock + ev.delta_frames as usize;
+                            state.note_on(pitch, velocity, at);
+                        }
+                        Status::ControlChange | Status::PitchBend |
+                        Status::ChannelAftertouch | Status::PolyphonicAftertouch => {
+                            if let Some((source, input)) = ControlSource::from_midi(ev.data) {
+                                let mut controllers = mem::take(&mut state.controllers);
+                                for controller in controllers.iter_mut().filter(|c| c.source == source) {
+                                    let current = state.param_values[controller.param];
+                                    if let Some(val) = controller.value(input, current) {
+                                        state.param_changed(&mut self.state.host, ParamId::from(controller.param), val);
+                                        // let the host know, like it was automated
+                                        let val = state.param_values[controller.param];
+                                        let (min, max) = state.param_ranges[controller.param];
+                                        let normalized = if max > min { (val - min) / (max - min) } else { 0. };
+                                        self.state.host.automate(controller.param as i32, normalized);
+                                    }
                                 }
-                                _ => {}
+                                state.controllers = controllers;
+                            }
+                        }
+                        Status::ProgramChange => {
+                            if let Some(ref library) = state.library {
+                                info!("program change: {}", ev.data[1]);
+                                library.load(ev.data[1] as usize);
                             }
                         }
-                        Status::NoteOff => {
+                        Status::NoteOn | Status::NoteOff => {
                             let pitch = ev.data[1];
                             info!("Pitch: {}", pitch);
-                            match pitch {
-                                D3_PITCH => {
-                                    state.state = looper_cycle(state, Commands::ReplaceStop);
-                                }
-                                B2_PITCH => {
-                                    state.state = looper_cycle(state, Commands::InsertStop);
-                                }
-                                A2_PITCH => {
-                                    state.state = looper_cycle(state, Commands::MultiplyStop);
-                                }
-                                _ => {}
-                            }
+                            state.note_off(pitch);
                         }
                         _ => {}
                     }
@@ -358,159 +1410,147 @@ impl EasyVst<ParamId, ELState> for ELPlugin {
             }
         }
 
+        // notes that are held long enough
+        while let Some(note) = state.gestures.long_press(state.clock) {
+            if let Some(binding) = find_binding(&state.bindings, note, Gesture::LongPress) {
+                state.prev_state = state.state;
+                state.state = looper_cycle(state, binding.command);
+                info!("long press {}, new state: {}", binding.command, state.state);
+            }
+        }
+
 //        let send_buffer = &mut state.send_buffer;
 //        send_buffer.store_midi(&self.state.user_state.events);
 //        self.state.host.process_events(send_buffer.events());
         state.events.clear();
 
-        for (index, (left_in, right_in)) in stereo_in.enumerate() {
-
-            // select the buffer we are recording into
-            let mut record_buffer = &mut state.buffer;
-            // let play_buffer = &state.buffers[state.read_idx];
-
-            // see if we need to change the state for a sync stop
-            match state.state {
-                LooperState::SyncStop(command) => {
-                    let pos = write_position + index;
-                    match command {
-                        Commands::ReplaceStop => {
-                            // if we are not in the same subdivision then see if we can stop the replace
-                            if state.sync_subdivision != state.subdivision {
-                                if (pos >= state.sync_point) & (pos < state.sync_point + state.sync_window) {
-                                    info!("sync point reached: {}/{} - stopping {} {} -> {}", state.sync_point, pos, state.state, command, state.return_state);
-
-                                    state.state = state.return_state;
-                                    state.sync_subdivision = 0;
-                                }
-                            } else {
-                                // info!("Not stopping, still in same subdivision");
-                            }
-                        }
-                        Commands::InsertStop => {
-                            if (pos % state.insert_sync_len) < state.sync_window {
-                                info!("insert sync point reached: {}/{} - stopping {} {} -> {}", state.insert_sync_len, pos, state.state, command, state.return_state);
-
-                                state.state = state.return_state;
-                            }
+        // loops saved and loaded by the library
+        while let Some(response) = state.library.as_ref().and_then(|library| library.receive()) {
+            match response {
+                library::Response::Loaded(loaded) => {
+                    if state.disk.is_some() {
+                        info!("library loops can't be played as disk loops");
+                    } else if let Some(old) = state.next_loop.replace(loaded) {
+                        if let Some(ref library) = state.library {
+                            library.free(old.buffer);
                         }
-                        _ => {}
                     }
                 }
-                _ => {}
+                library::Response::Saved(name) => info!("loop saved as {}", name),
+                library::Response::Failed(e) => info!("{}", e),
             }
+        }
+        // room to save the loop without allocating here, disk loops aren't saved
+        if state.disk.is_none() {
+            if let Some(ref mut library) = state.library {
+                library.keep_up(state.loop_length, state.buffer.channels());
+            }
+        }
+        // a stopped looper takes the new loop right away
+        if state.state == LooperState::Stopped && state.next_loop.is_some() {
+            recall_loop(state, 0);
+        }
 
-            match state.state {
-                LooperState::Recording => {
-                    // Push the new samples into the loop buffers.
-
-                    if (state.write_position + index) < record_buffer.buffer.len() {
-                        if let Some((left_old, right_old)) = record_buffer.buffer.get_mut(write_position + index) {
-                            *left_old = left_in.as_f32();
-                            *right_old = right_in.as_f32();
-                        }
-                    } else {
-                        record_buffer.buffer.push((left_in.as_f32(), right_in.as_f32()));
-                    }
-
-                    state.loop_length += 1;
-                    state.cycle_len += 1;
-                }
-                LooperState::Inserting | LooperState::SyncStop(Commands::InsertStop) => {
-                    if let Some((left_old, right_old)) = record_buffer.buffer.get_mut(write_position + index) {
-                        *left_old = left_in.as_f32();
-                        *right_old = right_in.as_f32();
-                        state.loop_length += 1;
-                        state.cycle_len += 1;
-                    }
-                }
-                LooperState::Overdubbing => {
-                    record_buffer.overdub(write_position + index, (left_in.as_f32(), right_in.as_f32()), state.feedback);
-                }
-                LooperState::Replacing | LooperState::SyncStop(Commands::ReplaceStop) => {
-                    record_buffer.overwrite(write_position + index, (left_in.as_f32(), right_in.as_f32()));
-                }
-                LooperState::SyncStart(command) => {
-                    let pos = write_position + index;
-                    if (pos >= state.sync_point) & (pos < state.sync_point + state.sync_window) {
-                        info!("sync point reached: {}/{} - {}", state.sync_point, pos, command);
-                        state.state = match command {
-                            Commands::ReplaceStart => {
-                                // store the current subdivision, so we don't stop replacing too soon
-                                state.sync_subdivision = state.subdivision;
-                                LooperState::Replacing
-                            } ,
-                            Commands::InsertStart => {
-                                state.insert_sync_len = state.division_len; // store the current length of a subdivision
-                                record_buffer.insert_empty(write_position + index, stereo_in_len - index);
-                                // info!("switching, extended buffer at {} : {}, new len {}", write_position + index, stereo_in_len - index, record_buffer.length());
-                                LooperState::Inserting
-                            }
-                            _ => state.state
-                        }
-                    };
-                }
+        if let Some(ref mut disk) = state.disk {
+            disk.update(state.play_position);
+        }
 
-                LooperState::Multiplying => {
-                    // copy from the currently playing buffer to the (new) recording buffer
+        // the loop channels are set up when recording starts, only the host knows how many inputs there are,
+        // a new routing doesn't allocate
+        let channels = loop_store(&mut state.buffer, &mut state.disk).channels();
+        if !state.routing.fits(num_inputs, channels) {
+            state.routing = Routing::new(state.routing.mode, num_inputs, channels);
+        }
 
-//                    if let Some((left_old, right_old)) = play_buffer.buffer.get(play_position + index) {
-//                        let left_new = (*left_old * WET_MULT) * state.feedback + left_in.as_f32();
-//                        let right_new = (*right_old * WET_MULT) * state.feedback + right_in.as_f32();
-//                        record_buffer.buffer.push_back((left_new.as_f32(), right_new.as_f32()));
-//                    }
-                }
+        // sized when the host set the block size, this only allocates for a bigger block than the host announced
+        state.block.reserve(samples);
 
-                _ => {}
+        // the input of the whole block, routed to the loop channels
+        for index in 0..samples {
+            let input_gain = state.input_level.next();
+            let mut input = [0.0; MAX_CHANNELS];
+            for (i, sample) in input.iter_mut().enumerate().take(num_inputs) {
+                *sample = inputs[i][index].as_f32() * input_gain;
             }
+            let frame = &mut state.block.input[index * channels..(index + 1) * channels];
+            state.routing.route(&input[..num_inputs], frame);
         }
 
+        // record in runs of frames, between the sync points
+        let mut index = 0;
+        while index < samples {
+            // synced commands that are due now
+            while let Some(command) = state.scheduler.due(state.clock + index) {
+                state.sync_reached(command, index, samples);
+            }
+            index = state.record_run(index, samples, write_position, channels);
+        }
 
-        // play back from the play buffer
-        for (index, (left_out, right_out)) in stereo_out.enumerate() {
-            let play_buffer = &state.buffer;
+        // play back from the loop, in runs of frames up to the end of the loop
+        state.loop_level.fill(&mut state.block.loop_gains[..samples]);
+        state.dry_level.fill(&mut state.block.dry_gains[..samples]);
+        let mut index = 0;
+        while index < samples {
+            index = state.play_run(index, samples, channels);
+        }
+        apply_gains(&mut state.block.play[..samples * channels], channels, &state.block.loop_gains[..samples]);
+
+        // the click can have outputs 3 and 4 to itself, a count-in without the click is mixed in
+        let separate_click = state.metronome.mode == ClickMode::Separate && num_outputs > 3;
+
+        // loop channels are repeated over the outputs (a mono loop plays on both sides)
+        for index in 0..samples {
+            let processed = &state.block.play[index * channels..(index + 1) * channels];
+            let click = state.block.clicks[index];
+            let dry_gain = state.block.dry_gains[index];
+            for output in 0..num_outputs {
+                let dry = if output < num_inputs { inputs[output][index].as_f32() * dry_gain } else { 0. };
+                outputs[output][index] = if separate_click && (output == 2 || output == 3) {
+                    click.as_()
+                } else if separate_click {
+                    (processed[output % channels] + dry).as_()
+                } else {
+                    (processed[output % channels] + dry + click).as_()
+                };
+            }
+        }
 
-            let mut left_processed: f32 = 0.0;
-            let mut right_processed: f32 = 0.0;
+        // midi clock for other gear, following the loop
+        if state.send_clock(samples) {
+            self.state.host.process_events(state.send_buffer.events());
+        }
 
-            match state.state {
-                LooperState::Muted | LooperState::Stopped => {
-                    left_processed = 0. as f32;
-                    right_processed = 0. as f32;
-                }
-                _ => {
-                    if let Some((left_old, right_old)) = play_buffer.buffer.get(play_position + index) {
-                        const WET_MULT: f32 = 0.98;
+        state.advance(samples);
 
-                        left_processed = *left_old * WET_MULT;
-                        right_processed = *right_old * WET_MULT;
-                    }
-                }
-            }
+        // the sync master lets the others know where its cycle is, there is no cycle yet while recording
+        let recording = state.state == LooperState::Recording ||
+            state.state == LooperState::SyncStop(Commands::Record);
+        if state.cycle_len > 0 && state.state != LooperState::Stopped && !recording {
+            let cycle_start = state.clock.saturating_sub(state.play_position % state.cycle_len);
+            state.sync_bus.publish(state.clock, state.cycle_len, cycle_start);
+        } else {
+            state.sync_bus.publish(state.clock, 0, 0);
+        }
 
-            *left_out = left_processed.as_();
-            *right_out = right_processed.as_();
+        if state.state != state.reported_state {
+            state.reported_state = state.state;
+            let value = state.state.index() as f32 / (STATE_COUNT - 1) as f32;
+            self.state.host.automate(ParamId::State as i32, value);
         }
 
-        match state.state {
-            // update the write position
-            LooperState::Recording | LooperState::Inserting | LooperState::Overdubbing |
-            LooperState::Replacing | LooperState::SyncStart(_) | LooperState::SyncStop(_) => {
-                state.write_position += stereo_in_len;
-                state.write_position = state.write_position % state.loop_length;
-            }
-            _ => {}
+        if let Some(ref link) = state.ui_link {
+            link.publish(state.snapshot());
         }
 
-        if state.state != LooperState::Stopped {
-            state.play_position += stereo_out_len;
-            state.play_position = if state.loop_length > 0 {
-                state.play_position % state.loop_length
-            } else { 0 };
-            state.division_len = (state.cycle_len / state.division) as usize;
-            state.subdivision = if state.division_len > 0 {
-                (state.play_position / state.division_len) as usize
-            } else { 0 }
+        if let Some(ref osc) = state.osc {
+            osc.publish(Telemetry {
+                state: state.state,
+                play_position: state.play_position,
+                loop_length: state.loop_length,
+                position: state.bar_beat_tick(),
+                bpm: state.bpm() as f32,
+                bars: state.bars() as f32,
+            });
         }
 
         // info!("loop_len / write_pos / play_pos {} / {} / {} ", state.loop_length, state.write_position, state.play_position);
@@ -530,7 +1570,52 @@ impl EasyVst<ParamId, ELState> for ELPlugin {
 }
 
 
-fn status(b: u8) -> Status { b.into() }
+/// the number of channels of the store the loop is kept in
+fn loop_store_channels(buffer: &RecordingBuffer, disk: &Option<DiskStore>) -> usize {
+    match *disk {
+        Some(ref disk) => disk.channels(),
+        None => buffer.channels(),
+    }
+}
+
+/// the store the loop is kept in, on disk if there is one
+fn loop_store<'a>(buffer: &'a mut RecordingBuffer, disk: &'a mut Option<DiskStore>) -> &'a mut LoopStore {
+    match *disk {
+        Some(ref mut disk) => disk,
+        None => buffer,
+    }
+}
+
+fn midi_event(data: [u8; 3], delta_frames: usize) -> MidiEvent {
+    MidiEvent {
+        data,
+        delta_frames: delta_frames as i32,
+        live: true,
+        note_length: None,
+        note_offset: None,
+        detune: 0,
+        note_off_velocity: 0,
+    }
+}
+
+/// the input and output latency the host reports (audioMasterGetInputLatency and audioMasterGetOutputLatency),
+/// None when it doesn't know them
+fn host_latency(host: &HostCallback) -> Option<usize> {
+    let callback = host.raw_callback()?;
+    let effect = host.raw_effect();
+    let input = callback(effect, OpCode::GetInputLatency as i32, 0, 0, ptr::null_mut(), 0.);
+    let output = callback(effect, OpCode::GetOutputLatency as i32, 0, 0, ptr::null_mut(), 0.);
+    if input <= 0 && output <= 0 {
+        None
+    } else {
+        Some((input.max(0) + output.max(0)) as usize)
+    }
+}
+
+// the low nibble of a voice message is its channel, every channel is the same to the looper
+fn status(b: u8) -> Status {
+    if b < 0xF0 { (b & 0xF0).into() } else { b.into() }
+}
 
 impl_clike!(Status);
 
@@ -571,11 +1656,44 @@ mod ui;
 use std::os::raw::c_void;
 
 
+impl ui::LoopList for LibraryRemote {
+    fn names(&self) -> Vec<String> {
+        LibraryRemote::names(self)
+    }
+
+    fn load(&self, index: usize) {
+        LibraryRemote::load(self, index)
+    }
+}
+
+/// the transport buttons of the editor send their commands to the engine like the UI's other input
+pub struct TransportButtons {
+    sender: UiSender,
+    toggles: Toggles,
+}
+
+impl ui::Transport for TransportButtons {
+    fn click(&mut self, command: &str) {
+        match command.parse::<Commands>() {
+            Ok(command) => {
+                let command = self.toggles.click(command);
+                if !self.sender.send(UiInput::Command(command)) {
+                    info!("UI queue full, dropped {}", command);
+                }
+            }
+            Err(e) => info!("{}", e),
+        }
+    }
+}
+
 const WINDOW_WIDTH: u32 = 480;
-const WINDOW_HEIGHT: u32 = 160;
+const WINDOW_HEIGHT: u32 = 190;
 
 pub struct UiState {
     window: ui::PluginWindow,
+    transport: Option<Arc<Mutex<TransportButtons>>>,
+    counter: String,
+    // display current position in bar.beat.tick (or seconds while there is no loop)
 }
 
 #[derive(Debug)]
@@ -584,31 +1702,58 @@ pub enum AppError {
 }
 
 impl UiState {
-    pub fn new(my_folder: &Path, parent: *mut c_void) -> Option<Self> {
-        let window = ui::PluginWindow::new(Window::new_with_parent(parent).unwrap());
-        Some(UiState { window: window })
+    pub fn new(library: Option<ui::SharedLoopList>, transport: Option<Arc<Mutex<TransportButtons>>>,
+               parent: *mut c_void) -> Option<Self> {
+        let buttons = transport.clone().map(|transport| transport as ui::SharedTransport);
+        let window = ui::PluginWindow::new(Window::new_with_parent(parent).unwrap(), library, buttons);
+        Some(UiState { window, transport, counter: String::new() })
     }
 
-    pub fn draw(&mut self, state: &mut ELState) {
-        let window = self.window;
-
-        let sample_rate = *state.sample_rate.read().unwrap().deref();
-        let seconds = state.play_position as f64 / sample_rate;
-        let seconds = format!("{:.*}", 2, seconds);
-        if seconds != state.seconds {
-            window.counter.set_text(&seconds.to_string());
-            state.seconds = seconds;
+    /// show the newest snapshot of the engine, if there is one
+    pub fn draw(&mut self, link: &UiLink) {
+        let snapshot = match link.latest() {
+            Some(snapshot) => snapshot,
+            None => return,
+        };
+        // the buttons of held commands know if the engine is still in them
+        if let Some(ref transport) = self.transport {
+            if let Ok(mut transport) = transport.lock() {
+                transport.toggles.update(snapshot.state);
+            }
+        }
+        let window = &self.window;
+
+        let counter = if snapshot.cycle_len > 0 {
+            snapshot.position.to_string()
+        } else {
+            format!("{:.*}", 2, snapshot.seconds)
+        };
+        if counter != self.counter {
+            window.counter.set_text(&counter);
+            self.counter = counter;
         }
 
-        let cycles = format!("{} | {}", state.cycles, state.total_cycles);
-        let division = format!("{}", state.division);
-        let subdiv = format!("{} - {}", state.subdivision, state.sync_point);
+        let cycles = format!("{} | {}", snapshot.cycles, snapshot.total_cycles);
+        let division = format!("{}", snapshot.division);
+        let subdiv = format!("{:.2} bars | {:.1} BPM", snapshot.bars, snapshot.bpm);
         window.cycle_label.set_text(&cycles.to_string());
         window.division_label.set_text(&division.to_string());
         window.subdiv_label.set_text(&subdiv.to_string());
-
-        match state.state {
-            LooperState::Recording | LooperState::Overdubbing | LooperState::Replacing => {
+        let loop_name = snapshot.loop_name();
+        let mode = format!("{}{}{}{}{}", snapshot.insert_mode,
+                           if snapshot.reverse { " <<" } else { "" },
+                           if snapshot.half_speed { " 1/2" } else { "" },
+                           match snapshot.sync_role {
+                               sync_bus::SyncRole::Off => "",
+                               sync_bus::SyncRole::Master => " | Master",
+                               sync_bus::SyncRole::Slave => " | Slave",
+                           },
+                           if loop_name.is_empty() { String::new() } else { format!(" | {}", loop_name) });
+        window.mode_label.set_text(&mode);
+
+        match snapshot.state {
+            LooperState::Recording | LooperState::Overdubbing | LooperState::Replacing |
+            LooperState::Substituting | LooperState::Rehearsing => {
                 window.state_label.set_text_color(Color::red());
                 window.counter.set_text_color(Color::red());
             }
@@ -622,7 +1767,7 @@ impl UiState {
             }
             _ => {}
         }
-        window.state_label.set_text(&state.state.to_string());
+        window.state_label.set_text(&snapshot.state.to_string());
     }
 }
 
@@ -635,7 +1780,7 @@ impl Editor for ELPlugin {
     fn open(&mut self, parent: *mut c_void) {
         info!("open {}", parent as usize);
 
-        self.ui = UiState::new(&self.state.user_state.my_folder, parent);
+        self.ui = UiState::new(self.loop_list.clone(), self.transport.clone(), parent);
 
 
 //        thread::spawn( || {
@@ -658,17 +1803,58 @@ impl Editor for ELPlugin {
     }
 
     fn idle(&mut self) {
-        if let Some(ref mut ui) = self.ui {
-            ui.draw(&mut self.state.user_state);
+        if let (Some(ui), Some(link)) = (self.ui.as_mut(), self.ui_link.as_ref()) {
+            ui.draw(link);
         }
     }
 
     fn is_open(&mut self) -> bool {
         self.ui.is_some()
     }
+
+    fn key_down(&mut self, keycode: KeyCode) -> bool {
+        if shortcut(keycode.character).is_none() {
+            return false;
+        }
+        if let Some(command) = self.keys.down(keycode.character) {
+            self.send_command(command);
+        }
+        true
+    }
+
+    fn key_up(&mut self, keycode: KeyCode) -> bool {
+        if shortcut(keycode.character).is_none() {
+            return false;
+        }
+        if let Some(command) = self.keys.up(keycode.character) {
+            self.send_command(command);
+        }
+        true
+    }
 }
 
 impl ELPlugin {
+    /// send *command* to the engine, like a note of the bindings
+    fn send_command(&self, command: Commands) {
+        if let Some(ref link) = self.ui_link {
+            if !link.send(UiInput::Command(command)) {
+                info!("UI queue full, dropped {}", command);
+            }
+        }
+    }
+
+    /// the round trip latency of the audio interface for Latency Mode Auto, as the host reports it
+    /// or, when it doesn't, one block of *block_size* in and one block out
+    fn update_host_latency(&mut self, block_size: usize) {
+        let samples = match host_latency(&self.state.host) {
+            Some(samples) => samples,
+            None => 2 * block_size,
+        };
+        let state = &mut self.state.user_state;
+        state.latency.set_host_latency(samples);
+        info!("latency: {} samples", state.latency.samples());
+    }
+
     fn clear_buffers() -> Vec<RecordingBuffer> {
         const NUM_BUFFERS: usize = 4;
         let mut buffers = Vec::new();
@@ -680,3 +1866,118 @@ impl ELPlugin {
         buffers
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status() {
+        assert_eq!(status(0x90), Status::NoteOn);
+        assert_eq!(status(0x9F), Status::NoteOn);
+        assert_eq!(status(0x83), Status::NoteOff);
+        assert_eq!(status(0xB5), Status::ControlChange);
+        assert_eq!(status(0xE1), Status::PitchBend);
+        // a program change recalls a loop whatever channel it is on
+        assert_eq!(status(0xC0), Status::ProgramChange);
+        assert_eq!(status(0xC9), Status::ProgramChange);
+        assert_eq!(status(0xF8), Status::TimingClock);
+    }
+
+    // what process does with a block of *samples* samples, without the host's buffers
+    fn run_block(state: &mut ELState, samples: usize) {
+        let channels = loop_store(&mut state.buffer, &mut state.disk).channels();
+        let write_position = state.write_position;
+        state.block.reserve(samples);
+        let mut index = 0;
+        while index < samples {
+            while let Some(command) = state.scheduler.due(state.clock + index) {
+                state.sync_reached(command, index, samples);
+            }
+            index = state.record_run(index, samples, write_position, channels);
+        }
+        let mut index = 0;
+        while index < samples {
+            index = state.play_run(index, samples, channels);
+        }
+        state.advance(samples);
+    }
+
+    #[test]
+    fn test_count_in_from_empty() {
+        let mut state = ELState::default();
+        state.sample_rate = 48000.;
+        state.division = 8;
+        state.metronome.count_in = 1;
+        state.state = looper_cycle(&mut state, Commands::Record);
+        assert!(state.state == LooperState::SyncStart(Commands::Record));
+        // nothing is recorded yet while counting in
+        run_block(&mut state, 64);
+        assert_eq!(state.loop_length, 0);
+        assert_eq!(state.write_position, 0);
+        assert_eq!(state.play_position, 0);
+    }
+
+    #[test]
+    fn test_param_range() {
+        let mut state = ELState::default();
+        let params = ELPlugin::params();
+        state.param_values = params.iter().map(|p| p.default).collect();
+        state.param_ranges = params.iter().map(|p| (p.min, p.max)).collect();
+        let mut host = HostCallback::default();
+        // like /plex/param/division 0 and /plex/param/stutter_slice 100 over OSC
+        state.param_changed(&mut host, ParamId::Division, 0.);
+        assert_eq!(state.division, 1);
+        state.param_changed(&mut host, ParamId::StutterSlice, 100.);
+        assert_eq!(state.stutter_slice, 4);
+        assert_eq!(state.param_values[ParamId::StutterSlice as usize], 2.);
+        state.param_changed(&mut host, ParamId::Division, f32::NAN);
+        assert_eq!(state.division, 1);
+    }
+
+    #[test]
+    fn test_velocity_level() {
+        let mut state = ELState::default();
+        state.param_values = ELPlugin::params().iter().map(|p| p.default).collect();
+        state.param_values[ParamId::InputLevel as usize] = 0.8;
+        let mut overdub = NoteBinding::new(64, Commands::Overdub);
+        overdub.velocity = VelocityTarget::InputLevel;
+        state.fire(&overdub, 127);
+        let mut levels = [0.0; 10000];
+        state.input_level.fill(&mut levels);
+        assert!((levels[9999] - 1.0).abs() < 1e-3);
+        // without velocity the host's level is back
+        state.fire(&NoteBinding::new(67, Commands::Stop), 127);
+        state.input_level.fill(&mut levels);
+        assert!((levels[9999] - 0.8).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_tap_or_long_press() {
+        let mut state = ELState::default();
+        state.bindings = default_bindings();
+        state.gestures = Gestures::new(1000, 500);
+        // a tap on Record records as soon as the note goes on
+        state.note_on(69, 100, 0);
+        assert!(state.state == LooperState::Recording);
+        state.note_off(69);
+        assert!(state.state == LooperState::Recording);
+
+        // holding it clears what the tap recorded
+        state.note_on(69, 100, 2000);
+        assert!(state.state != LooperState::Recording);
+        while let Some(note) = state.gestures.long_press(3000) {
+            let binding = find_binding(&state.bindings, note, Gesture::LongPress).unwrap();
+            state.fire(&binding, 100);
+        }
+        state.note_off(69);
+        assert!(state.state == LooperState::Stopped);
+        assert_eq!(state.loop_length, 0);
+
+        // other notes fire when they go on too
+        state.loop_length = 800;
+        state.state = LooperState::Playing;
+        state.note_on(60, 100, 4000);
+        assert!(state.state == LooperState::Muted);
+    }
+}
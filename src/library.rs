@@ -0,0 +1,477 @@
+// a library of loops, kept as wav files in the library folder of the PlexLooper folder
+//
+// loops are saved and loaded by a background thread, the audio thread only passes buffers back and forth.
+// A loop is recalled by its number in the library (the files sorted by name), e.g. by a program change.
+// The UI has its own queue to the thread, and reads the names of the loops.
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use recording_buffer::{RecordingBuffer, MAX_CHANNELS};
+use resample;
+use spsc::{self, Producer, Consumer};
+
+const QUEUE_SIZE: usize = 16;
+// how long the thread sleeps when there is nothing to do
+const POLL_INTERVAL: u64 = 5;
+
+pub enum Request {
+    // save the first *length* frames of the buffer, a name is made up if there is none
+    Save { name: Option<String>, buffer: RecordingBuffer, length: usize },
+    // load the loop with this number
+    Load(usize),
+    // drop a buffer the audio thread is done with
+    Free(RecordingBuffer),
+    // make room in the spare buffer for a loop of this many samples, it goes back through saved
+    Grow { buffer: RecordingBuffer, samples: usize },
+}
+
+pub struct LoadedLoop {
+    pub name: String,
+    pub buffer: RecordingBuffer,
+    pub length: usize,
+}
+
+pub enum Response {
+    Loaded(LoadedLoop),
+    Saved(String),
+    Failed(String),
+}
+
+/// the part of the library the UI uses
+pub struct LibraryRemote {
+    requests: Producer<Request>,
+    names: Arc<Mutex<Vec<String>>>,
+}
+
+impl LibraryRemote {
+    pub fn load(&self, index: usize) {
+        let _ = self.requests.push(Request::Load(index));
+    }
+
+    /// the names of the loops in the library
+    pub fn names(&self) -> Vec<String> {
+        self.names.lock().map(|names| names.clone()).unwrap_or_default()
+    }
+}
+
+pub struct Library {
+    requests: Producer<Request>,
+    responses: Consumer<Response>,
+    spare: Option<RecordingBuffer>,
+    // what a loop is copied into to be saved, it goes to the library thread and comes back through saved
+    saved: Consumer<RecordingBuffer>,
+    remote: Arc<Mutex<LibraryRemote>>,
+    sample_rate: Arc<AtomicUsize>,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Library {
+    /// start the library thread for the loops in *folder*
+    pub fn start(folder: &Path) -> io::Result<Library> {
+        fs::create_dir_all(folder)?;
+        let (requests, thread_requests) = spsc::channel(QUEUE_SIZE);
+        let (ui_requests, thread_ui_requests) = spsc::channel(QUEUE_SIZE);
+        let (thread_responses, responses) = spsc::channel(QUEUE_SIZE);
+        let (thread_saved, saved) = spsc::channel(1);
+        let names = Arc::new(Mutex::new(list_loops(folder)));
+        let sample_rate = Arc::new(AtomicUsize::new(44100));
+        let running = Arc::new(AtomicBool::new(true));
+
+        let mut library_thread = LibraryThread {
+            folder: folder.to_path_buf(),
+            requests: thread_requests,
+            ui_requests: thread_ui_requests,
+            responses: thread_responses,
+            saved: thread_saved,
+            names: names.clone(),
+            sample_rate: sample_rate.clone(),
+        };
+        let thread_running = running.clone();
+        let thread = thread::Builder::new()
+            .name("plexlooper library".to_string())
+            .spawn(move || library_thread.run(&thread_running))?;
+
+        Ok(Library {
+            requests,
+            responses,
+            spare: Some(RecordingBuffer::new()),
+            saved,
+            remote: Arc::new(Mutex::new(LibraryRemote { requests: ui_requests, names })),
+            sample_rate,
+            running,
+            thread: Some(thread),
+        })
+    }
+
+    /// loops are loaded at this rate
+    pub fn set_sample_rate(&self, sample_rate: f64) {
+        self.sample_rate.store(sample_rate.round() as usize, Ordering::Relaxed);
+    }
+
+    pub fn remote(&self) -> Arc<Mutex<LibraryRemote>> {
+        self.remote.clone()
+    }
+
+    /// save the first *length* frames of *buffer*, never blocks
+    ///
+    /// the frames are copied into the spare buffer, which `keep_up` keeps big enough, so a save doesn't allocate
+    pub fn save(&mut self, name: Option<String>, buffer: &RecordingBuffer, length: usize) {
+        if self.spare.is_none() {
+            self.spare = self.saved.pop();
+        }
+        let mut copy = match self.spare.take() {
+            Some(copy) => copy,
+            None => {
+                info!("still saving the last loop, loop not saved");
+                return;
+            }
+        };
+        if copy.buffer.capacity() < length * buffer.channels() {
+            info!("the library is still making room, loop not saved");
+            self.spare = Some(copy);
+            return;
+        }
+        copy.copy_from(buffer, length);
+        if let Err(Request::Save { buffer, .. }) = self.requests.push(Request::Save { name, buffer: copy, length }) {
+            info!("library busy, loop not saved");
+            self.spare = Some(buffer);
+        }
+    }
+
+    /// have room in the spare for a loop of *length* frames of *channels* channels, called after every block
+    ///
+    /// the library thread makes the spare bigger, with room to spare so a growing loop doesn't send it every block
+    pub fn keep_up(&mut self, length: usize, channels: usize) {
+        if self.spare.is_none() {
+            self.spare = self.saved.pop();
+        }
+        let samples = length * channels;
+        match self.spare.take() {
+            Some(spare) if spare.buffer.capacity() < samples => {
+                if let Err(Request::Grow { buffer, .. }) = self.requests.push(Request::Grow { buffer: spare, samples }) {
+                    self.spare = Some(buffer);
+                }
+            }
+            spare => self.spare = spare,
+        }
+    }
+
+    /// load loop number *index*, it comes back through `receive`
+    pub fn load(&self, index: usize) {
+        let _ = self.requests.push(Request::Load(index));
+    }
+
+    /// let the library thread free a buffer, so the audio thread doesn't have to
+    pub fn free(&self, buffer: RecordingBuffer) {
+        // if the queue is full it is dropped here after all
+        let _ = self.requests.push(Request::Free(buffer));
+    }
+
+    /// the next answer of the library thread, never blocks
+    pub fn receive(&self) -> Option<Response> {
+        self.responses.pop()
+    }
+}
+
+impl Drop for Library {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+struct LibraryThread {
+    folder: PathBuf,
+    requests: Consumer<Request>,
+    ui_requests: Consumer<Request>,
+    responses: Producer<Response>,
+    saved: Producer<RecordingBuffer>,
+    names: Arc<Mutex<Vec<String>>>,
+    sample_rate: Arc<AtomicUsize>,
+}
+
+impl LibraryThread {
+    fn run(&mut self, running: &AtomicBool) {
+        while running.load(Ordering::Acquire) {
+            match self.requests.pop().or_else(|| self.ui_requests.pop()) {
+                Some(request) => self.handle(request),
+                None => thread::sleep(Duration::from_millis(POLL_INTERVAL)),
+            }
+        }
+    }
+
+    fn handle(&mut self, request: Request) {
+        let response = match request {
+            Request::Save { name, buffer, length } => {
+                let name = match name {
+                    Some(name) => clean_name(&name),
+                    None => self.new_name(),
+                };
+                let path = self.folder.join(format!("{}.wav", name));
+                let sample_rate = self.sample_rate.load(Ordering::Relaxed) as f64;
+                let response = match write_wav(&path, &buffer, length, sample_rate) {
+                    Ok(_) => Response::Saved(name),
+                    Err(e) => Response::Failed(format!("can't save {}: {}", name, e)),
+                };
+                // the audio thread saves the next loop into it
+                let _ = self.saved.push(buffer);
+                self.rescan();
+                response
+            }
+            Request::Load(index) => {
+                self.rescan();
+                let name = self.names.lock().ok().and_then(|names| names.get(index).cloned());
+                match name {
+                    Some(name) => match self.load(&name) {
+                        Ok(loaded) => Response::Loaded(loaded),
+                        Err(e) => Response::Failed(format!("can't load {}: {}", name, e)),
+                    },
+                    None => Response::Failed(format!("there is no loop {}", index)),
+                }
+            }
+            Request::Free(buffer) => {
+                drop(buffer);
+                return;
+            }
+            Request::Grow { mut buffer, samples } => {
+                buffer.buffer.clear();
+                buffer.buffer.reserve(samples * 2);
+                let _ = self.saved.push(buffer);
+                return;
+            }
+        };
+        // if the audio thread doesn't keep up the answer is lost
+        let _ = self.responses.push(response);
+    }
+
+    fn load(&self, name: &str) -> io::Result<LoadedLoop> {
+        let (buffer, length, file_rate) = read_wav(&self.folder.join(format!("{}.wav", name)))?;
+        let sample_rate = self.sample_rate.load(Ordering::Relaxed) as f64;
+        let (buffer, length) = if file_rate != sample_rate && length > 0 {
+            (resample::resample(&buffer, length, file_rate, sample_rate), resample::scale(length, file_rate, sample_rate))
+        } else {
+            (buffer, length)
+        };
+        Ok(LoadedLoop { name: name.to_string(), buffer, length })
+    }
+
+    fn rescan(&self) {
+        if let Ok(mut names) = self.names.lock() {
+            *names = list_loops(&self.folder);
+        }
+    }
+
+    /// the first free "loop <n>"
+    fn new_name(&self) -> String {
+        let names = list_loops(&self.folder);
+        (1..).map(|n| format!("loop {}", n)).find(|name| !names.contains(name)).unwrap()
+    }
+}
+
+/// only letters, digits, spaces, - and _ in file names
+fn clean_name(name: &str) -> String {
+    let name: String = name.chars()
+        .filter(|c| c.is_alphanumeric() || *c == ' ' || *c == '-' || *c == '_')
+        .collect();
+    if name.trim().is_empty() { "loop".to_string() } else { name.trim().to_string() }
+}
+
+/// the names of the loops in *folder*, sorted
+pub fn list_loops(folder: &Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(folder).map(|entries| {
+        entries.filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "wav"))
+            .filter_map(|path| path.file_stem().map(|stem| stem.to_string_lossy().to_string()))
+            .collect()
+    }).unwrap_or_default();
+    names.sort();
+    names
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn u16_at(data: &[u8], pos: usize) -> u16 {
+    data[pos] as u16 | (data[pos + 1] as u16) << 8
+}
+
+fn u32_at(data: &[u8], pos: usize) -> u32 {
+    u16_at(data, pos) as u32 | (u16_at(data, pos + 2) as u32) << 16
+}
+
+/// write the first *length* frames of *buffer* as a 32 bit float wav file
+pub fn write_wav(path: &Path, buffer: &RecordingBuffer, length: usize, sample_rate: f64) -> io::Result<()> {
+    let channels = buffer.channels();
+    let length = length.min(buffer.length());
+    let data_size = (length * channels * 4) as u32;
+    let rate = sample_rate.round() as u32;
+    let mut out = BufWriter::new(File::create(path)?);
+    out.write_all(b"RIFF")?;
+    out.write_all(&(36 + data_size).to_le_bytes())?;
+    out.write_all(b"WAVEfmt ")?;
+    out.write_all(&16u32.to_le_bytes())?;
+    // IEEE float
+    out.write_all(&3u16.to_le_bytes())?;
+    out.write_all(&(channels as u16).to_le_bytes())?;
+    out.write_all(&rate.to_le_bytes())?;
+    out.write_all(&(rate * channels as u32 * 4).to_le_bytes())?;
+    out.write_all(&(channels as u16 * 4).to_le_bytes())?;
+    out.write_all(&32u16.to_le_bytes())?;
+    out.write_all(b"data")?;
+    out.write_all(&data_size.to_le_bytes())?;
+    for sample in buffer.buffer[..length * channels].iter() {
+        out.write_all(&sample.to_bits().to_le_bytes())?;
+    }
+    out.flush()
+}
+
+/// read a wav file (16, 24 or 32 bit integer, or 32 bit float), returns the loop, its length and sample rate
+pub fn read_wav(path: &Path) -> io::Result<(RecordingBuffer, usize, f64)> {
+    let mut data = Vec::new();
+    BufReader::new(File::open(path)?).read_to_end(&mut data)?;
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+        return Err(invalid("not a wav file"));
+    }
+    let mut format = None;
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let id = &data[pos..pos + 4];
+        let size = u32_at(&data, pos + 4) as usize;
+        let body = pos + 8;
+        let end = (body + size).min(data.len());
+        if id == b"fmt " && size >= 16 {
+            // format, channels, sample rate, bits per sample
+            format = Some((u16_at(&data, body), u16_at(&data, body + 2) as usize,
+                           u32_at(&data, body + 4) as f64, u16_at(&data, body + 14)));
+        } else if id == b"data" {
+            let (tag, channels, sample_rate, bits) = format.ok_or_else(|| invalid("data before format"))?;
+            if channels == 0 || channels > MAX_CHANNELS {
+                return Err(invalid("unsupported number of channels"));
+            }
+            let samples: Vec<f32> = match (tag, bits) {
+                (3, 32) => data[body..end].chunks(4).filter(|s| s.len() == 4)
+                    .map(|s| f32::from_bits(u32_at(s, 0))).collect(),
+                (1, 16) | (0xFFFE, 16) => data[body..end].chunks(2).filter(|s| s.len() == 2)
+                    .map(|s| u16_at(s, 0) as i16 as f32 / 32768.).collect(),
+                (1, 24) | (0xFFFE, 24) => data[body..end].chunks(3).filter(|s| s.len() == 3)
+                    .map(|s| ((s[0] as i32) << 8 | (s[1] as i32) << 16 | (s[2] as i32) << 24) as f32 / 2147483648.)
+                    .collect(),
+                (1, 32) | (0xFFFE, 32) => data[body..end].chunks(4).filter(|s| s.len() == 4)
+                    .map(|s| u32_at(s, 0) as i32 as f32 / 2147483648.).collect(),
+                _ => return Err(invalid("unsupported sample format")),
+            };
+            let length = samples.len() / channels;
+            let mut buffer = RecordingBuffer::with_channels(length, channels);
+            buffer.buffer.copy_from_slice(&samples[..length * channels]);
+            return Ok((buffer, length, sample_rate));
+        }
+        // chunks are padded to an even size
+        pos = body + size + size % 2;
+    }
+    Err(invalid("no audio in the file"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_wav_round_trip() {
+        let path = env::temp_dir().join("plexlooper_test_round_trip.wav");
+        let mut buffer = RecordingBuffer::with_channels(0, 2);
+        for i in 0..100 {
+            buffer.push(&[i as f32 / 100., -(i as f32) / 100.]);
+        }
+        write_wav(&path, &buffer, 80, 48000.).unwrap();
+        let (loaded, length, sample_rate) = read_wav(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        assert_eq!((length, sample_rate, loaded.channels()), (80, 48000., 2));
+        assert_eq!(loaded.get(79), buffer.get(79));
+    }
+
+    #[test]
+    fn test_names() {
+        assert_eq!(clean_name("my/../loop"), "myloop");
+        assert_eq!(clean_name("  "), "loop");
+    }
+
+    // the first answer of the library thread *wanted* says yes to
+    fn wait_for<F: Fn(&Response) -> bool>(library: &Library, wanted: F) -> Option<Response> {
+        for _ in 0..200 {
+            match library.receive() {
+                Some(ref response) if !wanted(response) => {}
+                Some(response) => return Some(response),
+                None => thread::sleep(Duration::from_millis(5)),
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let folder = env::temp_dir().join("plexlooper_test_library");
+        let _ = fs::remove_dir_all(&folder);
+        let mut library = Library::start(&folder).unwrap();
+        library.set_sample_rate(48000.);
+        let mut buffer = RecordingBuffer::with_channels(0, 1);
+        for i in 0..64 {
+            buffer.push(&[i as f32]);
+        }
+        library.save(None, &buffer, 64);
+        // one save at a time, this one is dropped
+        library.save(Some("x".to_string()), &buffer, 64);
+        assert!(wait_for(&library, |response| matches!(*response, Response::Saved(_))).is_some());
+        library.save(Some("b/ass".to_string()), &buffer, 32);
+        library.load(1);
+        let loaded = match wait_for(&library, |response| matches!(*response, Response::Loaded(_))) {
+            Some(Response::Loaded(loaded)) => loaded,
+            _ => panic!("no loop loaded"),
+        };
+        // sorted by name: "bass", "loop 1"
+        assert_eq!(loaded.name, "loop 1");
+        assert_eq!(loaded.length, 64);
+        assert_eq!(library.remote().lock().unwrap().names(), vec!["bass".to_string(), "loop 1".to_string()]);
+        drop(library);
+        let _ = fs::remove_dir_all(&folder);
+    }
+
+    #[test]
+    fn test_keep_up() {
+        let folder = env::temp_dir().join("plexlooper_test_keep_up");
+        let _ = fs::remove_dir_all(&folder);
+        let mut library = Library::start(&folder).unwrap();
+        let buffer = RecordingBuffer::with_channels(300000, 1);
+        // longer than the spare, it isn't copied on the audio thread
+        library.save(None, &buffer, 300000);
+        assert!(library.spare.is_some());
+        library.keep_up(300000, 1);
+        for _ in 0..200 {
+            library.keep_up(300000, 1);
+            if library.spare.is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        let capacity = library.spare.as_ref().unwrap().buffer.capacity();
+        assert!(capacity >= 300000);
+        library.save(None, &buffer, 300000);
+        assert!(wait_for(&library, |response| matches!(*response, Response::Saved(_))).is_some());
+        // the copy didn't grow
+        library.keep_up(300000, 1);
+        assert_eq!(library.spare.as_ref().unwrap().buffer.capacity(), capacity);
+        drop(library);
+        let _ = fs::remove_dir_all(&folder);
+    }
+}
@@ -0,0 +1,118 @@
+// Loop windowing: only a part (the window) of the recorded material is played back,
+// the window can be moved through the recording and resized without losing any audio
+// see https://www.youtube.com/playlist?list=PLRjhe9qWtn00cegswVPoUGSU-tQa07DQH
+
+#[derive(Default)]
+pub struct LoopWindow {
+    pub enabled: bool,
+    // where in the recording does the window start
+    pub start: usize,
+    // length of the window in samples
+    pub length: usize,
+    // play position relative to the window start
+    pub offset: usize,
+}
+
+impl LoopWindow {
+    /// open a window of *length* samples at *start*, continuing playback at *position*
+    pub fn open(&mut self, start: usize, length: usize, position: usize) {
+        if length == 0 {
+            return;
+        }
+        self.enabled = true;
+        self.start = start;
+        self.length = length;
+        self.offset = position.saturating_sub(start) % length;
+    }
+
+    pub fn close(&mut self) {
+        self.enabled = false;
+        self.offset = 0;
+    }
+
+    /// move the window by *steps* of *step* samples, wrapping around a recording of *total* samples
+    pub fn shift(&mut self, steps: isize, step: usize, total: usize) {
+        if total == 0 {
+            return;
+        }
+        let distance = (steps.unsigned_abs() * step) % total;
+        self.start = if steps >= 0 {
+            (self.start + distance) % total
+        } else {
+            (self.start + total - distance) % total
+        };
+    }
+
+    /// grow or shrink the window by *steps* of *step* samples, it is at least one step and
+    /// at most *total* samples long, a recording shorter than a step is the whole window
+    pub fn resize(&mut self, steps: isize, step: usize, total: usize) {
+        if step == 0 || total == 0 {
+            return;
+        }
+        let length = (self.length as isize + steps * step as isize).max(step as isize) as usize;
+        self.length = length.min(total);
+        self.offset %= self.length;
+    }
+
+    /// index into the recording of the sample *offset* samples after the current position
+    pub fn index(&self, offset: usize, total: usize) -> usize {
+        if self.length == 0 || total == 0 {
+            return 0;
+        }
+        (self.start + (self.offset + offset) % self.length) % total
+    }
+
+    /// advance the play position inside the window by *samples*
+    pub fn advance(&mut self, samples: usize) {
+        if self.length > 0 {
+            self.offset = (self.offset + samples) % self.length;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open() {
+        let mut w = LoopWindow::default();
+        w.open(100, 50, 120);
+        assert!(w.enabled);
+        assert_eq!(w.offset, 20);
+        assert_eq!(w.index(0, 1000), 120);
+    }
+
+    #[test]
+    fn test_index_wraps_inside_window() {
+        let mut w = LoopWindow::default();
+        w.open(100, 50, 100);
+        assert_eq!(w.index(49, 1000), 149);
+        assert_eq!(w.index(50, 1000), 100);
+        w.advance(60);
+        assert_eq!(w.index(0, 1000), 110);
+    }
+
+    #[test]
+    fn test_index_wraps_around_recording() {
+        let mut w = LoopWindow::default();
+        w.open(0, 100, 0);
+        w.shift(-1, 50, 400);
+        assert_eq!(w.start, 350);
+        assert_eq!(w.index(60, 400), 10);
+    }
+
+    #[test]
+    fn test_resize() {
+        let mut w = LoopWindow::default();
+        w.open(0, 100, 0);
+        w.resize(2, 100, 250);
+        assert_eq!(w.length, 250);
+        w.resize(-5, 100, 250);
+        assert_eq!(w.length, 100);
+        // the recording is shorter than a step
+        w.resize(-1, 100, 60);
+        assert_eq!(w.length, 60);
+        assert!(w.index(70, 60) < 60);
+    }
+}
@@ -1,12 +1,19 @@
 use std::fmt;
+use std::mem;
+use std::str::FromStr;
 use ELState;
 use recording_buffer::RecordingBuffer;
 use ELPlugin;
+use shuffle::ShuffleMode;
+use resample;
+use recording_buffer::LoopStore;
+use fixed_length::{AfterRecord, FixedMode};
 
 // State machine of the looper
 // based on https://www.youtube.com/watch?v=b8slVcXtg3k
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Default)]
 pub enum LooperState {
+    #[default]
     Stopped,
     Recording,
     Clearing,
@@ -18,13 +25,93 @@ pub enum LooperState {
     SyncStop(Commands),
     Inserting,
     Muted,
+    // replacing while the old loop is still heard
+    Substituting,
+    // the first loop is recorded again on every pass, until Record keeps it
+    Rehearsing,
 }
 
+/// what the Insert function does, like the InsertMode of the Echoplex
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum InsertMode {
+    // insert new material into the loop
+    #[default]
+    Insert,
+    // toggle reverse playback
+    Reverse,
+    // toggle half speed playback
+    HalfSpeed,
+    // replace while still hearing the old loop
+    Substitute,
+    // Insert while recording the first loop starts rehearsing it
+    Rehearse,
+}
 
+impl From<usize> for InsertMode {
+    fn from(val: usize) -> InsertMode {
+        match val {
+            1 => InsertMode::Reverse,
+            2 => InsertMode::HalfSpeed,
+            3 => InsertMode::Substitute,
+            4 => InsertMode::Rehearse,
+            _ => InsertMode::Insert,
+        }
+    }
+}
 
+impl fmt::Display for InsertMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let printable = match *self {
+            InsertMode::Insert => "Insert",
+            InsertMode::Reverse => "Reverse",
+            InsertMode::HalfSpeed => "Half Speed",
+            InsertMode::Substitute => "Substitute",
+            InsertMode::Rehearse => "Rehearse",
+        };
+        write!(f, "{}", printable)
+    }
+}
 
-impl Default for LooperState {
-    fn default() -> LooperState { LooperState::Stopped }
+/// how many states there are, when sync states are counted once
+pub const STATE_COUNT: usize = 13;
+
+impl LooperState {
+    /// a number for the state, e.g. for reporting it as a parameter
+    pub fn index(&self) -> usize {
+        match *self {
+            LooperState::Stopped => 0,
+            LooperState::Recording => 1,
+            LooperState::Clearing => 2,
+            LooperState::Overdubbing => 3,
+            LooperState::Multiplying => 4,
+            LooperState::Playing => 5,
+            LooperState::Replacing => 6,
+            LooperState::SyncStart(_) => 7,
+            LooperState::SyncStop(_) => 8,
+            LooperState::Inserting => 9,
+            LooperState::Muted => 10,
+            LooperState::Substituting => 11,
+            LooperState::Rehearsing => 12,
+        }
+    }
+
+    pub fn from_index(index: usize) -> LooperState {
+        match index {
+            1 => LooperState::Recording,
+            2 => LooperState::Clearing,
+            3 => LooperState::Overdubbing,
+            4 => LooperState::Multiplying,
+            5 => LooperState::Playing,
+            6 => LooperState::Replacing,
+            7 => LooperState::SyncStart(Commands::ReplaceStart),
+            8 => LooperState::SyncStop(Commands::ReplaceStop),
+            9 => LooperState::Inserting,
+            10 => LooperState::Muted,
+            11 => LooperState::Substituting,
+            12 => LooperState::Rehearsing,
+            _ => LooperState::Stopped,
+        }
+    }
 }
 
 impl fmt::Display for LooperState {
@@ -41,12 +128,14 @@ impl fmt::Display for LooperState {
             LooperState::SyncStop(_command) => "Sync Stop",
             LooperState::Inserting => "Inserting",
             LooperState::Muted => "Muted",
+            LooperState::Substituting => "Substituting",
+            LooperState::Rehearsing => "Rehearsing",
         };
         write!(f, "{}", printable)
     }
 }
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum Commands {
     Stop,
     Play,
@@ -60,6 +149,20 @@ pub enum Commands {
     InsertStart,
     InsertStop,
     Mute,
+    Window,  // loop windowing on/off
+    WindowForward,  // move the window by one subdivision
+    WindowBackward,
+    WindowCycleForward,  // move the window by one cycle
+    WindowCycleBackward,
+    WindowGrow,  // resize the window by one subdivision
+    WindowShrink,
+    StutterStart,  // repeat the current subdivision while held
+    StutterStop,
+    ShufflePrint,  // make the shuffled order permanent
+    Undo,
+    Clear,  // empty the loop and stop
+    Reset,  // clear and go back to normal playback
+    SaveLoop,  // save the loop into the library
 }
 
 impl fmt::Display for Commands {
@@ -74,14 +177,78 @@ impl fmt::Display for Commands {
             Commands::MultiplyStop => "MultiplyStop",
             Commands::ReplaceStart => "ReplaceStart",
             Commands::ReplaceStop => "ReplaceStop",
-            Commands::InsertStart => "InserstStart",
+            Commands::InsertStart => "InsertStart",
             Commands::InsertStop => "InsertStop",
             Commands::Mute => "Mute",
+            Commands::Window => "Window",
+            Commands::WindowForward => "WindowForward",
+            Commands::WindowBackward => "WindowBackward",
+            Commands::WindowCycleForward => "WindowCycleForward",
+            Commands::WindowCycleBackward => "WindowCycleBackward",
+            Commands::WindowGrow => "WindowGrow",
+            Commands::WindowShrink => "WindowShrink",
+            Commands::StutterStart => "StutterStart",
+            Commands::StutterStop => "StutterStop",
+            Commands::ShufflePrint => "ShufflePrint",
+            Commands::Undo => "Undo",
+            Commands::Clear => "Clear",
+            Commands::Reset => "Reset",
+            Commands::SaveLoop => "SaveLoop",
         };
         write!(f, "{}", printable)
     }
 }
 
+impl FromStr for Commands {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Commands, String> {
+        let command = match s {
+            "Stop" => Commands::Stop,
+            "Play" => Commands::Play,
+            "Record" => Commands::Record,
+            "Overdub" => Commands::Overdub,
+            "Multiply" => Commands::Multiply,
+            "MultiplyStart" => Commands::MultiplyStart,
+            "MultiplyStop" => Commands::MultiplyStop,
+            "ReplaceStart" => Commands::ReplaceStart,
+            "ReplaceStop" => Commands::ReplaceStop,
+            "InsertStart" => Commands::InsertStart,
+            "InsertStop" => Commands::InsertStop,
+            "Mute" => Commands::Mute,
+            "Window" => Commands::Window,
+            "WindowForward" => Commands::WindowForward,
+            "WindowBackward" => Commands::WindowBackward,
+            "WindowCycleForward" => Commands::WindowCycleForward,
+            "WindowCycleBackward" => Commands::WindowCycleBackward,
+            "WindowGrow" => Commands::WindowGrow,
+            "WindowShrink" => Commands::WindowShrink,
+            "StutterStart" => Commands::StutterStart,
+            "StutterStop" => Commands::StutterStop,
+            "ShufflePrint" => Commands::ShufflePrint,
+            "Undo" => Commands::Undo,
+            "Clear" => Commands::Clear,
+            "Reset" => Commands::Reset,
+            "SaveLoop" => Commands::SaveLoop,
+            _ => return Err(format!("unknown command: {}", s)),
+        };
+        Ok(command)
+    }
+}
+
+impl Commands {
+    /// the command to send when a held command is released
+    pub fn release(&self) -> Option<Commands> {
+        match *self {
+            Commands::MultiplyStart => Some(Commands::MultiplyStop),
+            Commands::ReplaceStart => Some(Commands::ReplaceStop),
+            Commands::InsertStart => Some(Commands::InsertStop),
+            Commands::StutterStart => Some(Commands::StutterStop),
+            _ => None,
+        }
+    }
+}
+
 
 pub fn  looper_cycle(plugin_state: &mut ELState, command: Commands) -> LooperState {
     use LooperState::*;
@@ -89,9 +256,43 @@ pub fn  looper_cycle(plugin_state: &mut ELState, command: Commands) -> LooperSta
 
     let state = plugin_state.state;
 
+    // stopping cancels what is waiting for a sync point
+    if command == Stop {
+        plugin_state.scheduler.clear();
+    }
+
     match(state, command) {
+        (_, Clear) => clear(plugin_state),
+        (_, Reset) => reset(plugin_state),
+
+        // a substitution ends on the release even when the insert mode changed while the key was held
+        (Substituting, InsertStop) => plugin_state.return_state,
+
+        // Insert does what the insert mode says
+        (_, InsertStart) | (_, InsertStop) if plugin_state.insert_mode != InsertMode::Insert => {
+            insert_function(plugin_state, command)
+        }
+
+        // loop windowing works while the loop is running and doesn't change the state
+        (Playing, Window) | (Overdubbing, Window) | (Muted, Window) => window_toggle(plugin_state),
+        (Playing, WindowForward) | (Overdubbing, WindowForward) | (Muted, WindowForward) => window_move(plugin_state, 1, false),
+        (Playing, WindowBackward) | (Overdubbing, WindowBackward) | (Muted, WindowBackward) => window_move(plugin_state, -1, false),
+        (Playing, WindowCycleForward) | (Overdubbing, WindowCycleForward) | (Muted, WindowCycleForward) => window_move(plugin_state, 1, true),
+        (Playing, WindowCycleBackward) | (Overdubbing, WindowCycleBackward) | (Muted, WindowCycleBackward) => window_move(plugin_state, -1, true),
+        (Playing, WindowGrow) | (Overdubbing, WindowGrow) | (Muted, WindowGrow) => window_resize(plugin_state, 1),
+        (Playing, WindowShrink) | (Overdubbing, WindowShrink) | (Muted, WindowShrink) => window_resize(plugin_state, -1),
+
+        // so is stuttering
+        (Playing, StutterStart) | (Overdubbing, StutterStart) => stutter_start(plugin_state),
+        (_, StutterStop) => stutter_stop(plugin_state),
+        (Playing, ShufflePrint) | (Muted, ShufflePrint) => shuffle_print(plugin_state),
+
+        // go back to the loop as it was before the last change
+        (Playing, Undo) | (Overdubbing, Undo) | (Replacing, Undo) | (Muted, Undo) => undo(plugin_state),
+        (Playing, SaveLoop) | (Muted, SaveLoop) | (Stopped, SaveLoop) => save_loop(plugin_state),
+
         (Stopped, Play) => Playing,
-        (Stopped, Record) => clearing_start(plugin_state),
+        (Stopped, Record) => record_start(plugin_state),
         (Stopped, Overdub) => overdub_start(plugin_state),
         (Stopped, _) => Stopped,
 
@@ -100,43 +301,57 @@ pub fn  looper_cycle(plugin_state: &mut ELState, command: Commands) -> LooperSta
         (Clearing, _) => Recording,
 
         (Playing, Stop) => Stopped,
-        (Playing, Record) => clearing_start(plugin_state),
+        (Playing, Record) => record_start(plugin_state),
         (Playing, Overdub) => overdub_start(plugin_state),
         (Playing, ReplaceStart) => replace_start(plugin_state),
         (Playing, InsertStart) => insert_start(plugin_state),
-        (Playing, MultiplyStart) => multiply_start(plugin_state),
+        (Playing, MultiplyStart) => synced_multiply_start(plugin_state),
         (Playing, Mute) => Muted,
         (Playing, _) => Playing,
 
         (Recording, Stop) => recording_stop(plugin_state, Stopped),
-        (Recording, Record) => recording_stop(plugin_state, Playing),
+        (Recording, Record) => synced_recording_stop(plugin_state),
         (Recording, Overdub) => overdub_start(plugin_state),
-        (Recording, MultiplyStart) => multiply_start(plugin_state),
-        (Recording, Play) => recording_stop( plugin_state, Playing),
+        (Recording, MultiplyStart) => synced_multiply_start(plugin_state),
+        (Recording, Play) => synced_recording_stop(plugin_state),
         (Recording, _) => Recording,
 
         (Overdubbing, Play) => Playing,
         (Overdubbing, Stop) => Stopped,
-        (Overdubbing, Record) => clearing_start(plugin_state),
+        (Overdubbing, Record) => record_start(plugin_state),
         (Overdubbing, Overdub) => Playing,
-        (Overdubbing, MultiplyStart) => multiply_start(plugin_state),
+        (Overdubbing, MultiplyStart) => synced_multiply_start(plugin_state),
         (Overdubbing, _) => Overdubbing,
 
-        (Multiplying, MultiplyStop) => multiply_end(plugin_state),
+        (Multiplying, MultiplyStop) => synced_multiply_end(plugin_state),
         (Multiplying, _) => Multiplying,
 
         (Replacing, ReplaceStop) => replace_stop(plugin_state),
         (Replacing, Stop) => Stopped,
         (Replacing, _) => Replacing,
 
-        (SyncStart(_command), ReplaceStop) => replace_stop(plugin_state),
-        (SyncStart(_command), InsertStop) => insert_stop(plugin_state),
-        (SyncStart(_command), _) => SyncStart(command),
-        (SyncStop(command), _) => SyncStop(command),
+        (SyncStart(ReplaceStart), ReplaceStop) => replace_stop(plugin_state),
+        (SyncStart(MultiplyStart), MultiplyStop) => synced_multiply_end(plugin_state),
+        (SyncStart(InsertStart), InsertStop) => insert_stop(plugin_state),
+        (SyncStart(Record), Stop) => {
+            plugin_state.counting_in = false;
+            Stopped
+        }
+        (SyncStop(Record), Stop) => recording_stop(plugin_state, Stopped),
+        (SyncStart(_), Stop) | (SyncStop(_), Stop) => Stopped,
+        (SyncStart(waiting), _) => SyncStart(waiting),
+        (SyncStop(waiting), _) => SyncStop(waiting),
 
         (Inserting, InsertStop) => insert_stop(plugin_state),
         (Inserting, _) => Inserting,
 
+        (Substituting, Stop) => Stopped,
+        (Substituting, _) => Substituting,
+
+        (Rehearsing, Record) => rehearse_stop(plugin_state, Playing),
+        (Rehearsing, Stop) => rehearse_stop(plugin_state, Stopped),
+        (Rehearsing, _) => Rehearsing,
+
         (Muted, Mute) => Playing,
         (Muted, _) => Muted,
         (_, Mute) => Muted,
@@ -150,15 +365,158 @@ fn clearing_start(plugin_state: &mut ELState) -> LooperState {
     plugin_state.play_position = 0;
     plugin_state.loop_length = 0;
     plugin_state.cycle_len = 0;
+    plugin_state.latency.clear_tail();
+    plugin_state.scheduler.clear();
+    plugin_state.counting_in = false;
+    plugin_state.window.close();
+    plugin_state.reverse = false;
+    plugin_state.half_speed = false;
+    plugin_state.half_phase = 0;
+    let sample_rate = plugin_state.sample_rate;
+    plugin_state.record_target = plugin_state.fixed_length.length(plugin_state.tempo.beats_per_bar, sample_rate);
+    if let Some(ref mut disk) = plugin_state.disk {
+        // the store stays, creating one means a file and a thread
+        disk.clear(plugin_state.loop_channels);
+    }
+    if plugin_state.buffer.channels() != plugin_state.loop_channels {
+        info!("loop channels: {}", plugin_state.loop_channels);
+        // the recording overwrites what is there
+        plugin_state.buffer.set_channels(plugin_state.loop_channels);
+    }
     LooperState::Recording
 }
+/// Record, or wait for the sync master's next cycle or a count-in to record
+fn record_start(plugin_state: &mut ELState) -> LooperState {
+    let count_in = plugin_state.count_in_len();
+    match plugin_state.sync_bus.next_sync(plugin_state.clock) {
+        Some(at) => {
+            info!("record waits for the sync master");
+            plugin_state.scheduler.clear();
+            plugin_state.scheduler.schedule(at, Commands::Record);
+            LooperState::SyncStart(Commands::Record)
+        }
+        None if count_in > 0 => {
+            info!("count in: {} samples", count_in);
+            plugin_state.scheduler.clear();
+            // the click starts counting now
+            plugin_state.click_start = plugin_state.clock;
+            plugin_state.counting_in = true;
+            plugin_state.scheduler.schedule(plugin_state.clock + count_in, Commands::Record);
+            LooperState::SyncStart(Commands::Record)
+        }
+        None => clearing_start(plugin_state),
+    }
+}
+
+fn synced_recording_stop(plugin_state: &mut ELState) -> LooperState {
+    match plugin_state.sync_bus.next_sync(plugin_state.clock) {
+        // the loop is a multiple of the master's cycle
+        Some(at) if !plugin_state.scheduler.is_pending(Commands::Record) => {
+            plugin_state.scheduler.schedule(at, Commands::Record);
+            LooperState::SyncStop(Commands::Record)
+        }
+        Some(_) => plugin_state.state,
+        None => recording_stop(plugin_state, LooperState::Playing),
+    }
+}
+
+fn synced_multiply_start(plugin_state: &mut ELState) -> LooperState {
+    // every command sets prev_state, the release of MultiplyStart too
+    plugin_state.return_state = plugin_state.state;
+    match plugin_state.sync_bus.next_sync(plugin_state.clock) {
+        Some(at) => {
+            plugin_state.scheduler.schedule(at, Commands::MultiplyStart);
+            LooperState::SyncStart(Commands::MultiplyStart)
+        }
+        None => multiply_start(plugin_state),
+    }
+}
+
+fn synced_multiply_end(plugin_state: &mut ELState) -> LooperState {
+    let cycle_len = plugin_state.sync_bus.cycle_len().unwrap_or(0);
+    let at = match plugin_state.scheduler.time_of(Commands::MultiplyStart) {
+        // released before it started, multiply by one cycle of the master
+        Some(start) => Some(start + cycle_len),
+        None => plugin_state.sync_bus.next_sync(plugin_state.clock),
+    };
+    match at {
+        Some(at) => {
+            if !plugin_state.scheduler.is_pending(Commands::MultiplyStop) {
+                plugin_state.scheduler.schedule(at, Commands::MultiplyStop);
+            }
+            plugin_state.state
+        }
+        None => multiply_end(plugin_state),
+    }
+}
+
+/// a command that waited for the sync master's cycle (or a count-in) is due
+pub fn master_sync_reached(plugin_state: &mut ELState, command: Commands) -> LooperState {
+    match (plugin_state.state, command) {
+        (LooperState::SyncStart(Commands::Record), Commands::Record) => clearing_start(plugin_state),
+        (LooperState::SyncStop(Commands::Record), Commands::Record) => recording_stop(plugin_state, LooperState::Playing),
+        (LooperState::SyncStart(Commands::MultiplyStart), Commands::MultiplyStart) => multiply_start(plugin_state),
+        (LooperState::Multiplying, Commands::MultiplyStop) => multiply_end(plugin_state),
+        (state, _) => state,
+    }
+}
+
+fn clear(plugin_state: &mut ELState) -> LooperState {
+    info!("clear");
+    plugin_state.write_position = 0;
+    plugin_state.play_position = 0;
+    plugin_state.loop_length = 0;
+    plugin_state.cycle_len = 0;
+    plugin_state.record_target = None;
+    plugin_state.latency.clear_tail();
+    plugin_state.scheduler.clear();
+    plugin_state.counting_in = false;
+    plugin_state.window.close();
+    plugin_state.stutter.stop();
+    plugin_state.rehearse.clear();
+    if let Some(ref mut disk) = plugin_state.disk {
+        let channels = disk.channels();
+        disk.clear(channels);
+    }
+    LooperState::Stopped
+}
+
+/// clear, and forget everything that changes how the loop plays, the parameters stay as the host has them
+fn reset(plugin_state: &mut ELState) -> LooperState {
+    info!("reset");
+    plugin_state.undo.saved = false;
+    plugin_state.reverse = false;
+    plugin_state.half_speed = false;
+    plugin_state.half_phase = 0;
+    clear(plugin_state)
+}
+
 fn recording_stop(plugin_state: &mut ELState, next_state: LooperState) -> LooperState {
     plugin_state.cycle_len = plugin_state.loop_length;
+    plugin_state.latency.start_tail(plugin_state.loop_length);
     info!("Stopping -> {}: cycle_len: {}", next_state, plugin_state.cycle_len);
     next_state
 }
 
+/// a fixed length recording reached its length
+pub fn fixed_recording_stop(plugin_state: &mut ELState) -> LooperState {
+    plugin_state.record_target = None;
+    // the loop has the bars of the Fixed Tempo, so it shows that tempo
+    if plugin_state.fixed_length.mode == FixedMode::Bars {
+        plugin_state.tempo.beats_per_cycle = plugin_state.fixed_length.bars.max(1) * plugin_state.tempo.beats_per_bar.max(1);
+    }
+    match plugin_state.fixed_length.after {
+        AfterRecord::Play => recording_stop(plugin_state, LooperState::Playing),
+        AfterRecord::Overdub => {
+            recording_stop(plugin_state, LooperState::Overdubbing);
+            overdub_start(plugin_state)
+        }
+        AfterRecord::Stop => recording_stop(plugin_state, LooperState::Stopped),
+    }
+}
+
 fn overdub_start(plugin_state: &mut ELState) -> LooperState {
+    save_undo(plugin_state);
     // plugin_state.loop_index += 1;
     plugin_state.write_position = plugin_state.play_position;
     LooperState::Overdubbing
@@ -166,39 +524,480 @@ fn overdub_start(plugin_state: &mut ELState) -> LooperState {
 
 fn replace_start(plugin_state: &mut ELState) -> LooperState {
     info!("replace start");
+    save_undo(plugin_state);
     plugin_state.write_position = plugin_state.play_position;
     plugin_state.return_state = plugin_state.state;
+    let at = plugin_state.next_sync(plugin_state.clock);
+    plugin_state.scheduler.schedule(at, Commands::ReplaceStart);
 
     LooperState::SyncStart(Commands::ReplaceStart)
 }
 
-fn replace_stop(_plugin_state: &mut ELState) -> LooperState {
+fn replace_stop(plugin_state: &mut ELState) -> LooperState {
     info!("replace stop");
-
-    LooperState::SyncStop(Commands::ReplaceStop)
+    if plugin_state.scheduler.is_pending(Commands::ReplaceStop) {
+        return plugin_state.state;
+    }
+    match plugin_state.scheduler.time_of(Commands::ReplaceStart) {
+        // the replace hasn't started yet, it replaces the one subdivision after the start
+        Some(start) => {
+            let at = plugin_state.next_sync(start);
+            plugin_state.scheduler.schedule(at, Commands::ReplaceStop);
+            plugin_state.state
+        }
+        None => {
+            let at = plugin_state.next_sync(plugin_state.clock);
+            plugin_state.scheduler.schedule(at, Commands::ReplaceStop);
+            LooperState::SyncStop(Commands::ReplaceStop)
+        }
+    }
 }
 
 
 fn insert_start(plugin_state: &mut ELState) -> LooperState {
     info!("insert start");
+    if plugin_state.disk.is_some() {
+        info!("insert is not available with disk loops");
+        return plugin_state.state;
+    }
+    save_undo(plugin_state);
     plugin_state.write_position = plugin_state.play_position;
     plugin_state.return_state = plugin_state.state;
+    // with a sync master, inserts follow its cycle
+    let at = plugin_state.sync_bus.next_sync(plugin_state.clock)
+        .unwrap_or_else(|| plugin_state.next_sync(plugin_state.clock));
+    plugin_state.scheduler.schedule(at, Commands::InsertStart);
 
     LooperState::SyncStart(Commands::InsertStart)
 }
 
-fn insert_stop(_plugin_state: &mut ELState) -> LooperState {
+fn insert_stop(plugin_state: &mut ELState) -> LooperState {
     info!("insert stop");
+    if plugin_state.scheduler.is_pending(Commands::InsertStop) {
+        return plugin_state.state;
+    }
+    match plugin_state.scheduler.time_of(Commands::InsertStart) {
+        // the insert hasn't started yet, it inserts one subdivision
+        Some(start) => {
+            let at = start + plugin_state.insert_unit();
+            plugin_state.scheduler.schedule(at, Commands::InsertStop);
+            plugin_state.state
+        }
+        // inserts are whole subdivisions long
+        None => {
+            let length = plugin_state.insert_sync_len;
+            let inserted = plugin_state.clock - plugin_state.insert_started;
+            let at = if length > 0 { plugin_state.clock + length - inserted % length } else { plugin_state.clock };
+            plugin_state.scheduler.schedule(at, Commands::InsertStop);
+            LooperState::SyncStop(Commands::InsertStop)
+        }
+    }
+}
 
-    LooperState::SyncStop(Commands::InsertStop)
+/// the Insert function in the other insert modes
+fn insert_function(plugin_state: &mut ELState, command: Commands) -> LooperState {
+    use LooperState::*;
+    let state = plugin_state.state;
+    match (plugin_state.insert_mode, state, command) {
+        (InsertMode::Reverse, Playing, Commands::InsertStart) |
+        (InsertMode::Reverse, Overdubbing, Commands::InsertStart) |
+        (InsertMode::Reverse, Muted, Commands::InsertStart) => {
+            plugin_state.reverse = !plugin_state.reverse;
+            info!("reverse: {}", plugin_state.reverse);
+            state
+        }
+        (InsertMode::HalfSpeed, Playing, Commands::InsertStart) |
+        (InsertMode::HalfSpeed, Overdubbing, Commands::InsertStart) |
+        (InsertMode::HalfSpeed, Muted, Commands::InsertStart) => {
+            plugin_state.half_speed = !plugin_state.half_speed;
+            plugin_state.half_phase = 0;
+            info!("half speed: {}", plugin_state.half_speed);
+            state
+        }
+        (InsertMode::Substitute, Playing, Commands::InsertStart) => substitute_start(plugin_state),
+        (InsertMode::Rehearse, Recording, Commands::InsertStart) => rehearse_start(plugin_state),
+        _ => state,
+    }
+}
+
+fn substitute_start(plugin_state: &mut ELState) -> LooperState {
+    info!("substitute start");
+    save_undo(plugin_state);
+    plugin_state.write_position = plugin_state.play_position;
+    plugin_state.return_state = plugin_state.state;
+    LooperState::Substituting
+}
+
+fn rehearse_start(plugin_state: &mut ELState) -> LooperState {
+    if plugin_state.disk.is_some() {
+        info!("rehearse is not available with disk loops");
+        return plugin_state.state;
+    }
+    recording_stop(plugin_state, LooperState::Rehearsing);
+    // the part of the loop that isn't recorded again stays as it was
+    plugin_state.rehearse.copy_from(&plugin_state.buffer, plugin_state.buffer.length());
+    plugin_state.write_position = plugin_state.play_position;
+    LooperState::Rehearsing
+}
+
+/// keep the loop as it was played in the last pass
+fn rehearse_stop(plugin_state: &mut ELState, next_state: LooperState) -> LooperState {
+    info!("rehearse stop -> {}", next_state);
+    plugin_state.rehearse.clear();
+    next_state
 }
 
 fn multiply_start(plugin_state: &mut ELState) -> LooperState {
-    let new_buffer = RecordingBuffer::with_size(plugin_state.cycle_len);
+    if plugin_state.disk.is_some() {
+        info!("multiply is not available with disk loops");
+        return plugin_state.state;
+    }
+    save_undo(plugin_state);
+    let new_buffer = RecordingBuffer::with_channels(plugin_state.cycle_len, plugin_state.buffer.channels());
     plugin_state.buffers.push(new_buffer);
     LooperState::Multiplying
 }
 
 fn multiply_end(plugin_state: &mut ELState) -> LooperState {
-    plugin_state.prev_state
+    plugin_state.return_state
+}
+
+fn window_toggle(plugin_state: &mut ELState) -> LooperState {
+    if plugin_state.window.enabled {
+        info!("window closed");
+        plugin_state.window.close();
+    } else {
+        // start with a window of the subdivision we are currently in
+        let start = plugin_state.subdivision * plugin_state.division_len;
+        plugin_state.window.open(start, plugin_state.division_len, plugin_state.play_position);
+        info!("window opened: {} / {}", plugin_state.window.start, plugin_state.window.length);
+    }
+    plugin_state.state
+}
+
+fn window_move(plugin_state: &mut ELState, steps: isize, by_cycle: bool) -> LooperState {
+    let step = if by_cycle { plugin_state.cycle_len } else { plugin_state.division_len };
+    plugin_state.window.shift(steps, step, plugin_state.loop_length);
+    info!("window moved: {} / {}", plugin_state.window.start, plugin_state.window.length);
+    plugin_state.state
+}
+
+fn window_resize(plugin_state: &mut ELState, steps: isize) -> LooperState {
+    plugin_state.window.resize(steps, plugin_state.division_len, plugin_state.loop_length);
+    info!("window resized: {} / {}", plugin_state.window.start, plugin_state.window.length);
+    plugin_state.state
+}
+
+fn stutter_start(plugin_state: &mut ELState) -> LooperState {
+    // repeat the slice of the current subdivision we are in
+    let slice_len = plugin_state.division_len / plugin_state.stutter_slice;
+    let subdivision_start = plugin_state.subdivision * plugin_state.division_len;
+    let into = plugin_state.play_position.saturating_sub(subdivision_start);
+    if let Some(slice) = into.checked_div(slice_len) {
+        let start = subdivision_start + slice * slice_len;
+        plugin_state.stutter.start(start, slice_len, plugin_state.play_position);
+        info!("stutter start: {} / {}", start, slice_len);
+    }
+    plugin_state.state
+}
+
+fn stutter_stop(plugin_state: &mut ELState) -> LooperState {
+    info!("stutter stop");
+    plugin_state.stutter.stop();
+    plugin_state.state
+}
+
+fn shuffle_print(plugin_state: &mut ELState) -> LooperState {
+    if plugin_state.shuffle.mode != ShuffleMode::Off && plugin_state.disk.is_none() {
+        info!("shuffle print: {:?}", plugin_state.shuffle.order());
+        save_undo(plugin_state);
+        plugin_state.buffer.reorder(plugin_state.loop_length, plugin_state.cycle_len,
+                                    plugin_state.division_len, plugin_state.shuffle.order());
+        // the buffer is in shuffled order now, don't shuffle it again
+        let divisions = plugin_state.division;
+        plugin_state.shuffle.set_mode(ShuffleMode::Off, divisions);
+    }
+    plugin_state.state
+}
+
+/// the loop as it was before a change
+#[derive(Default)]
+pub struct UndoSnapshot {
+    buffer: RecordingBuffer,
+    // kept from one snapshot to the next, so taking one copies into memory that is already there
+    loop_length: usize,
+    cycle_len: usize,
+    saved: bool,
+    // is there a loop to go back to
+}
+
+impl UndoSnapshot {
+    /// convert the saved loop from *from* to *to* Hz
+    pub fn resample(&mut self, from: f64, to: f64) {
+        if !self.saved {
+            return;
+        }
+        self.buffer = resample::resample(&self.buffer, self.loop_length, from, to);
+        self.loop_length = resample::scale(self.loop_length, from, to);
+        self.cycle_len = resample::scale(self.cycle_len, from, to);
+    }
+}
+
+fn save_undo(plugin_state: &mut ELState) {
+    // a disk loop is too long to copy
+    if plugin_state.disk.is_some() {
+        return;
+    }
+    let undo = &mut plugin_state.undo;
+    undo.buffer.copy_from(&plugin_state.buffer, plugin_state.buffer.length());
+    undo.loop_length = plugin_state.loop_length;
+    undo.cycle_len = plugin_state.cycle_len;
+    undo.saved = true;
+}
+
+/// hand a copy of the loop to the library, the state doesn't change
+fn save_loop(plugin_state: &mut ELState) -> LooperState {
+    if plugin_state.disk.is_some() {
+        info!("disk loops can't be saved to the library");
+    } else if plugin_state.loop_length == 0 {
+        info!("no loop to save");
+    } else if let Some(ref mut library) = plugin_state.library {
+        info!("saving loop of {} samples", plugin_state.loop_length);
+        library.save(None, &plugin_state.buffer, plugin_state.loop_length);
+    }
+    plugin_state.state
+}
+
+/// the loop loaded from the library takes over, *index* samples into the block
+///
+/// the new loop starts right there, the old one goes back to the library to be freed, its undo is forgotten
+pub fn recall_loop(plugin_state: &mut ELState, index: usize) {
+    let mut next = match plugin_state.next_loop.take() {
+        Some(next) => next,
+        None => return,
+    };
+    info!("recalling loop {} ({} samples)", next.name, next.length);
+    mem::swap(&mut plugin_state.buffer, &mut next.buffer);
+    plugin_state.loop_length = next.length;
+    plugin_state.cycle_len = next.length;
+    plugin_state.division_len = plugin_state.cycle_len / plugin_state.division.max(1);
+    plugin_state.cycles = 1;
+    plugin_state.total_cycles = 1;
+    let position = if next.length > 0 { (next.length - index % next.length) % next.length } else { 0 };
+    plugin_state.play_position = position;
+    plugin_state.write_position = position;
+    plugin_state.latency.clear_tail();
+    plugin_state.scheduler.clear();
+    plugin_state.window.close();
+    plugin_state.stutter.stop();
+    plugin_state.loop_name = next.name;
+    plugin_state.undo.saved = false;
+    if let Some(ref library) = plugin_state.library {
+        library.free(next.buffer);
+    }
+}
+
+fn undo(plugin_state: &mut ELState) -> LooperState {
+    if plugin_state.undo.saved {
+        let snapshot = &mut plugin_state.undo;
+        info!("undo: {} -> {}", plugin_state.loop_length, snapshot.loop_length);
+        // the loop that is undone keeps its memory for the next snapshot
+        mem::swap(&mut plugin_state.buffer, &mut snapshot.buffer);
+        snapshot.saved = false;
+        plugin_state.loop_length = snapshot.loop_length;
+        plugin_state.cycle_len = snapshot.cycle_len;
+        if plugin_state.loop_length > 0 {
+            plugin_state.play_position %= plugin_state.loop_length;
+        }
+        plugin_state.write_position = plugin_state.play_position;
+    }
+    match plugin_state.state {
+        LooperState::Muted => LooperState::Muted,
+        _ => LooperState::Playing,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn playing() -> ELState {
+        let mut state = ELState::default();
+        state.state = LooperState::Playing;
+        state.loop_length = 800;
+        state.cycle_len = 800;
+        state.division_len = 100;
+        state.play_position = 150;
+        state.clock = 1000;
+        state
+    }
+
+    #[test]
+    fn test_quantized_replace() {
+        // start and stop in the same subdivision replace exactly the next subdivision
+        let mut state = playing();
+        state.state = looper_cycle(&mut state, Commands::ReplaceStart);
+        state.state = looper_cycle(&mut state, Commands::ReplaceStop);
+        assert!(state.state == LooperState::SyncStart(Commands::ReplaceStart));
+        assert_eq!(state.scheduler.time_of(Commands::ReplaceStart), Some(1050));
+        assert_eq!(state.scheduler.time_of(Commands::ReplaceStop), Some(1150));
+
+        let command = state.scheduler.due(1050).unwrap();
+        state.sync_reached(command, 0, 64);
+        assert!(state.state == LooperState::SyncStop(Commands::ReplaceStop));
+        let command = state.scheduler.due(1150).unwrap();
+        state.sync_reached(command, 0, 64);
+        assert!(state.state == LooperState::Playing);
+    }
+
+    #[test]
+    fn test_stop_cancels_sync() {
+        let mut state = playing();
+        state.state = looper_cycle(&mut state, Commands::ReplaceStart);
+        state.state = looper_cycle(&mut state, Commands::Stop);
+        assert!(state.state == LooperState::Stopped);
+        assert_eq!(state.scheduler.due(2000), None);
+    }
+
+    #[test]
+    fn test_insert_mode() {
+        let mut state = playing();
+        state.insert_mode = InsertMode::Reverse;
+        state.state = looper_cycle(&mut state, Commands::InsertStart);
+        state.state = looper_cycle(&mut state, Commands::InsertStop);
+        assert!(state.state == LooperState::Playing && state.reverse);
+        assert_eq!(state.play_index(0), 649);
+
+        state.insert_mode = InsertMode::Substitute;
+        state.state = looper_cycle(&mut state, Commands::InsertStart);
+        assert!(state.state == LooperState::Substituting);
+        assert_eq!(state.write_position, 150);
+        state.state = looper_cycle(&mut state, Commands::InsertStop);
+        assert!(state.state == LooperState::Playing);
+
+        state.state = looper_cycle(&mut state, Commands::InsertStart);
+        state.insert_mode = InsertMode::Insert;
+        state.state = looper_cycle(&mut state, Commands::InsertStop);
+        assert!(state.state == LooperState::Playing);
+    }
+
+    #[test]
+    fn test_rehearse() {
+        let mut state = ELState::default();
+        state.insert_mode = InsertMode::Rehearse;
+        state.state = looper_cycle(&mut state, Commands::Record);
+        state.loop_length = 800;
+        state.state = looper_cycle(&mut state, Commands::InsertStart);
+        assert!(state.state == LooperState::Rehearsing);
+        assert_eq!(state.cycle_len, 800);
+        assert_eq!(state.rehearse.length(), state.buffer.length());
+        // playing or overdubbing doesn't end rehearsing, Record does
+        state.state = looper_cycle(&mut state, Commands::Overdub);
+        assert!(state.state == LooperState::Rehearsing);
+        state.state = looper_cycle(&mut state, Commands::Record);
+        assert!(state.state == LooperState::Playing);
+        assert_eq!(state.rehearse.length(), 0);
+        // the memory stays for the next rehearsal
+        assert!(state.rehearse.buffer.capacity() > 0);
+    }
+
+    #[test]
+    fn test_pass_starts() {
+        let mut state = playing();
+        state.play_position = 790;
+        assert!(state.pass_starts(10) && !state.pass_starts(9));
+        // reversed, the pass starts at the end of the loop
+        state.reverse = true;
+        assert!(state.pass_starts(10));
+        assert_eq!(state.play_index(10), 799);
+        // at half speed only once
+        state.reverse = false;
+        state.half_speed = true;
+        state.play_position = 799;
+        assert!(state.pass_starts(2) && !state.pass_starts(3));
+    }
+
+    #[test]
+    fn test_count_in() {
+        let mut state = ELState::default();
+        state.clock = 1000;
+        state.sample_rate = 48000.;
+        state.metronome.count_in = 1;
+        // a bar of 4 beats at 120 BPM
+        let count_in = state.count_in_len();
+        assert_eq!(count_in, 96000);
+        state.state = looper_cycle(&mut state, Commands::Record);
+        assert!(state.state == LooperState::SyncStart(Commands::Record));
+        assert_eq!(state.scheduler.time_of(Commands::Record), Some(1000 + count_in));
+
+        // recording starts exactly at the end of the count-in, 10 samples into a block
+        let command = state.scheduler.due(1000 + count_in).unwrap();
+        state.sync_reached(command, 10, 64);
+        assert!(state.state == LooperState::Recording);
+        assert_eq!(state.record_offset, 10);
+        assert!(!state.counting_in);
+    }
+
+    #[test]
+    fn test_fixed_recording_stop() {
+        let mut state = ELState::default();
+        state.sample_rate = 48000.;
+        state.fixed_length.mode = FixedMode::Bars;
+        state.fixed_length.bpm = 90.;
+        state.fixed_length.bars = 2;
+        state.fixed_length.after = AfterRecord::Overdub;
+        state.state = looper_cycle(&mut state, Commands::Record);
+        assert_eq!(state.record_target, Some(256000));
+        state.loop_length = 256000;
+        state.play_position = 0;
+        state.write_position = 1000;
+        state.state = fixed_recording_stop(&mut state);
+        assert!(state.state == LooperState::Overdubbing);
+        assert_eq!(state.tempo.beats_per_cycle, 8);
+        assert!((state.bpm() - 90.).abs() < 1e-9);
+        // the overdub starts like any other
+        assert_eq!(state.write_position, 0);
+        assert!(state.undo.saved);
+    }
+
+    #[test]
+    fn test_count_in_over_loop() {
+        let mut state = playing();
+        state.sample_rate = 48000.;
+        state.metronome.count_in = 1;
+        state.state = looper_cycle(&mut state, Commands::Record);
+        assert!(state.state == LooperState::SyncStart(Commands::Record));
+        // the loop's next beat is 50 samples away, the count-in clicks right away
+        state.click_beat_len = state.free_beat_len();
+        let clicks: Vec<f32> = (0..40).map(|index| state.click(index)).collect();
+        assert!(clicks.iter().any(|s| *s != 0.));
+    }
+
+    #[test]
+    fn test_undo() {
+        let mut state = playing();
+        state.buffer.overwrite(0, &[0.5, 0.5]);
+        state.state = looper_cycle(&mut state, Commands::Overdub);
+        state.buffer.overwrite(0, &[1.0, 1.0]);
+        state.loop_length = 400;
+        state.state = looper_cycle(&mut state, Commands::Undo);
+        assert!(state.state == LooperState::Playing);
+        assert_eq!(state.loop_length, 800);
+        assert_eq!(state.buffer.get(0), Some(&[0.5, 0.5][..]));
+        // there is only one step back
+        state.state = looper_cycle(&mut state, Commands::Undo);
+        assert_eq!(state.buffer.get(0), Some(&[0.5, 0.5][..]));
+    }
+
+    #[test]
+    fn test_clear_and_reset() {
+        let mut state = playing();
+        state.reverse = true;
+        state.state = looper_cycle(&mut state, Commands::Clear);
+        assert!(state.state == LooperState::Stopped);
+        assert_eq!(state.loop_length, 0);
+        assert!(state.reverse);
+        state.state = looper_cycle(&mut state, Commands::Reset);
+        assert!(!state.reverse);
+    }
 }
@@ -0,0 +1,123 @@
+// a click to play along to, and to count in a recording
+//
+// the click is a short sine burst, higher on the first beat of a bar
+
+use std::f64::consts::PI;
+use std::fmt;
+
+// length of a click
+const CLICK_MS: f64 = 30.;
+const ACCENT_HZ: f64 = 1500.;
+const BEAT_HZ: f64 = 1000.;
+
+/// where the click goes
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum ClickMode {
+    #[default]
+    Off,
+    // on top of the loop
+    Mixed,
+    // on outputs 3 and 4
+    Separate,
+}
+
+impl From<usize> for ClickMode {
+    fn from(val: usize) -> ClickMode {
+        match val {
+            1 => ClickMode::Mixed,
+            2 => ClickMode::Separate,
+            _ => ClickMode::Off,
+        }
+    }
+}
+
+impl fmt::Display for ClickMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let printable = match *self {
+            ClickMode::Off => "Off",
+            ClickMode::Mixed => "Mixed",
+            ClickMode::Separate => "Separate",
+        };
+        write!(f, "{}", printable)
+    }
+}
+
+/// the beat that starts at *position* on a grid of beats *beat_len* samples long, if one does
+pub fn beat_at(position: usize, beat_len: f64) -> Option<usize> {
+    if beat_len < 1. {
+        return None;
+    }
+    let beat = (position as f64 / beat_len).round();
+    if (beat * beat_len).round() as usize == position { Some(beat as usize) } else { None }
+}
+
+pub struct Metronome {
+    pub mode: ClickMode,
+    pub level: f32,
+    pub count_in: usize,
+    // bars to count in before recording
+    sample_rate: f64,
+    phase: usize,
+    // samples since the last click started
+    freq: f64,
+}
+
+impl Default for Metronome {
+    fn default() -> Metronome {
+        Metronome { mode: ClickMode::Off, level: 0.5, count_in: 0, sample_rate: 44100., phase: usize::MAX, freq: BEAT_HZ }
+    }
+}
+
+impl Metronome {
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+    }
+
+    /// start a click, *accent* for the first beat of a bar
+    pub fn trigger(&mut self, accent: bool) {
+        self.phase = 0;
+        self.freq = if accent { ACCENT_HZ } else { BEAT_HZ };
+    }
+
+    /// the next sample of the click
+    pub fn next(&mut self) -> f32 {
+        let length = self.sample_rate * CLICK_MS / 1000.;
+        if self.phase as f64 >= length {
+            return 0.;
+        }
+        let t = self.phase as f64 / self.sample_rate;
+        let envelope = 1. - self.phase as f64 / length;
+        self.phase += 1;
+        ((2. * PI * self.freq * t).sin() * envelope * envelope) as f32 * self.level
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_beat_at() {
+        // 120 BPM at 44.1kHz
+        let beat_len = 22050.;
+        assert_eq!(beat_at(0, beat_len), Some(0));
+        assert_eq!(beat_at(22049, beat_len), None);
+        assert_eq!(beat_at(88200, beat_len), Some(4));
+        assert_eq!(beat_at(5, 0.5), None);
+        // beats that aren't a whole number of samples long
+        assert_eq!(beat_at(201, 100.5), Some(2));
+        assert_eq!(beat_at(202, 100.5), None);
+    }
+
+    #[test]
+    fn test_click() {
+        let mut m = Metronome::default();
+        m.set_sample_rate(48000.);
+        assert_eq!(m.next(), 0.);
+        m.trigger(true);
+        let click: Vec<f32> = (0..2000).map(|_| m.next()).collect();
+        assert!(click.iter().any(|s| s.abs() > 0.1));
+        // it is over after 30ms
+        assert!(click[1440..].iter().all(|s| *s == 0.));
+    }
+}
@@ -0,0 +1,418 @@
+// OSC control and state reporting over UDP
+//
+// The server runs in its own thread, commands and parameter changes are passed to the audio thread
+// through a lock-free queue, the audio thread publishes its state the same way.
+//
+//     /plex/<command> [0|1]     trigger a command (Record, Overdub, ReplaceStart, ...)
+//                               held commands (ReplaceStart, InsertStart, ...) are released with an argument of 0
+//     /plex/param/<name> <f>    set a parameter, the name is lower case without spaces (feedback, drylevel, ...)
+//     /plex/subscribe [port]    get /plex/state, /plex/position and /plex/length messages (at port or the sender's port)
+//     /plex/unsubscribe [port]
+
+use std::io;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use looper_fsm::{Commands, LooperState};
+use tempo::BarBeatTick;
+use spsc::{self, Producer, Consumer};
+
+const QUEUE_SIZE: usize = 256;
+// how often do we send position updates to subscribers
+const POSITION_INTERVAL: u64 = 50;
+// how long do we wait for a packet before we check the queues again
+const POLL_INTERVAL: u64 = 10;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum OscArg {
+    Int(i32),
+    Float(f32),
+    Str(String),
+}
+
+impl OscArg {
+    fn as_f32(&self) -> Option<f32> {
+        match *self {
+            OscArg::Int(i) => Some(i as f32),
+            OscArg::Float(f) => Some(f),
+            OscArg::Str(_) => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OscMessage {
+    pub addr: String,
+    pub args: Vec<OscArg>,
+}
+
+fn read_string(data: &[u8], pos: &mut usize) -> Option<String> {
+    let start = *pos;
+    // the padding of the string before may be missing from a broken packet
+    let len = data.get(start..)?.iter().position(|b| *b == 0)?;
+    let s = String::from_utf8(data[start..start + len].to_vec()).ok()?;
+    // strings are null terminated and padded to 4 bytes
+    *pos = start + (len / 4 + 1) * 4;
+    Some(s)
+}
+
+fn read_u32(data: &[u8], pos: &mut usize) -> Option<u32> {
+    let bytes = data.get(*pos..*pos + 4)?;
+    *pos += 4;
+    Some((bytes[0] as u32) << 24 | (bytes[1] as u32) << 16 | (bytes[2] as u32) << 8 | bytes[3] as u32)
+}
+
+fn write_string(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(s.as_bytes());
+    let padding = 4 - s.len() % 4;
+    for _ in 0..padding {
+        out.push(0);
+    }
+}
+
+fn write_u32(out: &mut Vec<u8>, v: u32) {
+    out.extend_from_slice(&[(v >> 24) as u8, (v >> 16) as u8, (v >> 8) as u8, v as u8]);
+}
+
+impl OscMessage {
+    pub fn new(addr: &str, args: Vec<OscArg>) -> OscMessage {
+        OscMessage { addr: addr.to_string(), args }
+    }
+
+    /// decode a single OSC message, bundles are not supported
+    pub fn decode(data: &[u8]) -> Option<OscMessage> {
+        let mut pos = 0;
+        let addr = read_string(data, &mut pos)?;
+        if !addr.starts_with('/') {
+            return None;
+        }
+        let mut args = Vec::new();
+        if pos < data.len() {
+            let tags = read_string(data, &mut pos)?;
+            for tag in tags.chars().skip(1) {
+                let arg = match tag {
+                    'i' => OscArg::Int(read_u32(data, &mut pos)? as i32),
+                    'f' => OscArg::Float(f32::from_bits(read_u32(data, &mut pos)?)),
+                    's' => OscArg::Str(read_string(data, &mut pos)?),
+                    _ => return None,
+                };
+                args.push(arg);
+            }
+        }
+        Some(OscMessage { addr, args })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_string(&mut out, &self.addr);
+        let mut tags = ",".to_string();
+        for arg in self.args.iter() {
+            tags.push(match *arg {
+                OscArg::Int(_) => 'i',
+                OscArg::Float(_) => 'f',
+                OscArg::Str(_) => 's',
+            });
+        }
+        write_string(&mut out, &tags);
+        for arg in self.args.iter() {
+            match *arg {
+                OscArg::Int(i) => write_u32(&mut out, i as u32),
+                OscArg::Float(f) => write_u32(&mut out, f.to_bits()),
+                OscArg::Str(ref s) => write_string(&mut out, s),
+            }
+        }
+        out
+    }
+}
+
+/// what the OSC server sends to the audio thread
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OscInput {
+    Command(Commands),
+    // index of the parameter and its new value
+    Param(usize, f32),
+}
+
+/// what the audio thread publishes to OSC clients
+#[derive(Clone, Copy)]
+pub struct Telemetry {
+    pub state: LooperState,
+    pub play_position: usize,
+    pub loop_length: usize,
+    pub position: BarBeatTick,
+    pub bpm: f32,
+    pub bars: f32,
+}
+
+pub struct OscServer {
+    inputs: Consumer<OscInput>,
+    telemetry: Producer<Telemetry>,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    local_addr: SocketAddr,
+}
+
+impl OscServer {
+    /// start listening on *address* and *port*, *param_names* are the names of the plugin parameters by index
+    pub fn start(address: IpAddr, port: u16, param_names: Vec<String>) -> io::Result<OscServer> {
+        let socket = UdpSocket::bind((address, port))?;
+        socket.set_read_timeout(Some(Duration::from_millis(POLL_INTERVAL)))?;
+        let local_addr = socket.local_addr()?;
+
+        let (input_producer, inputs) = spsc::channel(QUEUE_SIZE);
+        let (telemetry, telemetry_consumer) = spsc::channel(QUEUE_SIZE);
+        let running = Arc::new(AtomicBool::new(true));
+
+        let thread_running = running.clone();
+        let param_names = param_names.iter().map(|name| name.to_lowercase().replace(" ", "")).collect();
+        let thread = thread::spawn(move || {
+            let mut server = ServerThread {
+                socket,
+                inputs: input_producer,
+                telemetry: telemetry_consumer,
+                param_names,
+                subscribers: Vec::new(),
+                state: None,
+            };
+            server.run(&thread_running);
+        });
+
+        Ok(OscServer { inputs, telemetry, running, thread: Some(thread), local_addr })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// the next command or parameter change received, never blocks
+    pub fn receive(&self) -> Option<OscInput> {
+        self.inputs.pop()
+    }
+
+    /// send the current state to the subscribers, never blocks
+    pub fn publish(&self, telemetry: Telemetry) {
+        // if the server can't keep up, the update is dropped
+        let _ = self.telemetry.push(telemetry);
+    }
+}
+
+impl Drop for OscServer {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn state_message(state: LooperState) -> OscMessage {
+    OscMessage::new("/plex/state", vec![OscArg::Str(state.to_string())])
+}
+
+struct ServerThread {
+    socket: UdpSocket,
+    inputs: Producer<OscInput>,
+    telemetry: Consumer<Telemetry>,
+    param_names: Vec<String>,
+    subscribers: Vec<SocketAddr>,
+    // the last state we have sent
+    state: Option<LooperState>,
+}
+
+impl ServerThread {
+    fn run(&mut self, running: &AtomicBool) {
+        let mut buf = [0u8; 1024];
+        let mut last_position = Instant::now();
+
+        while running.load(Ordering::Acquire) {
+            if let Ok((len, from)) = self.socket.recv_from(&mut buf) {
+                match OscMessage::decode(&buf[..len]) {
+                    Some(message) => self.handle(message, from),
+                    None => info!("OSC: can't decode packet from {}", from),
+                }
+            }
+
+            let mut latest = None;
+            while let Some(telemetry) = self.telemetry.pop() {
+                if self.state != Some(telemetry.state) {
+                    self.state = Some(telemetry.state);
+                    self.send(&state_message(telemetry.state));
+                }
+                latest = Some(telemetry);
+            }
+            if let Some(telemetry) = latest {
+                if last_position.elapsed() >= Duration::from_millis(POSITION_INTERVAL) {
+                    last_position = Instant::now();
+                    self.send(&OscMessage::new("/plex/position", vec![OscArg::Int(telemetry.play_position as i32)]));
+                    self.send(&OscMessage::new("/plex/length", vec![OscArg::Int(telemetry.loop_length as i32)]));
+                    self.send(&OscMessage::new("/plex/beat", vec![OscArg::Str(telemetry.position.to_string())]));
+                    self.send(&OscMessage::new("/plex/tempo", vec![OscArg::Float(telemetry.bpm)]));
+                    self.send(&OscMessage::new("/plex/bars", vec![OscArg::Float(telemetry.bars)]));
+                }
+            }
+        }
+        info!("OSC server stopped");
+    }
+
+    fn handle(&mut self, message: OscMessage, from: SocketAddr) {
+        let first = message.args.first();
+        let mut reply_to = from;
+        if let Some(port) = first.and_then(|arg| arg.as_f32()) {
+            reply_to.set_port(port as u16);
+        }
+
+        let input = match message.addr.as_str() {
+            "/plex/subscribe" => {
+                if !self.subscribers.contains(&reply_to) {
+                    info!("OSC: subscribed {}", reply_to);
+                    self.subscribers.push(reply_to);
+                }
+                // let the new subscriber know where we are
+                if let Some(state) = self.state {
+                    let _ = self.socket.send_to(&state_message(state).encode(), reply_to);
+                }
+                None
+            }
+            "/plex/unsubscribe" => {
+                self.subscribers.retain(|s| *s != reply_to);
+                None
+            }
+            addr if addr.starts_with("/plex/param/") => {
+                let name = &addr["/plex/param/".len()..];
+                let index = self.param_names.iter().position(|n| n == name);
+                match (index, first.and_then(|arg| arg.as_f32())) {
+                    (Some(index), Some(val)) => Some(OscInput::Param(index, val)),
+                    _ => None,
+                }
+            }
+            addr if addr.starts_with("/plex/") => {
+                match addr["/plex/".len()..].parse::<Commands>() {
+                    // an argument of 0 releases a held command
+                    Ok(command) => match (first.and_then(|arg| arg.as_f32()), command.release()) {
+                        (Some(0.), Some(release)) => Some(OscInput::Command(release)),
+                        (Some(0.), None) => None,
+                        _ => Some(OscInput::Command(command)),
+                    },
+                    Err(_) => None,
+                }
+            }
+            _ => None,
+        };
+
+        match input {
+            Some(input) => {
+                if self.inputs.push(input).is_err() {
+                    info!("OSC: queue full, dropping {:?}", input);
+                }
+            }
+            None => info!("OSC: {:?}", message),
+        }
+    }
+
+    fn send(&self, message: &OscMessage) {
+        let packet = message.encode();
+        for subscriber in self.subscribers.iter() {
+            let _ = self.socket.send_to(&packet, subscriber);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    fn server() -> (OscServer, UdpSocket, SocketAddr) {
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let server = OscServer::start(localhost, 0, vec!["Feedback".to_string(), "Dry Level".to_string()]).unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), server.local_addr().port()));
+        (server, client, addr)
+    }
+
+    fn wait_for_input(server: &OscServer) -> Option<OscInput> {
+        for _ in 0..100 {
+            if let Some(input) = server.receive() {
+                return Some(input);
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        None
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let message = OscMessage::new("/plex/param/feedback",
+                                      vec![OscArg::Float(0.5), OscArg::Int(-3), OscArg::Str("abcd".to_string())]);
+        let data = message.encode();
+        assert_eq!(data.len() % 4, 0);
+        assert_eq!(OscMessage::decode(&data), Some(message));
+    }
+
+    #[test]
+    fn test_decode_without_tags() {
+        let mut data = Vec::new();
+        write_string(&mut data, "/plex/Record");
+        assert_eq!(OscMessage::decode(&data), Some(OscMessage::new("/plex/Record", vec![])));
+        assert_eq!(OscMessage::decode(b"garbage"), None);
+    }
+
+    #[test]
+    fn test_decode_truncated() {
+        // the first string argument isn't padded, the second one is past the end
+        let mut data = Vec::new();
+        write_string(&mut data, "/plex/Record");
+        write_string(&mut data, ",ss");
+        data.extend_from_slice(b"x\0");
+        assert_eq!(OscMessage::decode(&data), None);
+        // an unpadded address without arguments is fine
+        assert_eq!(OscMessage::decode(b"/plex/Record\0"), Some(OscMessage::new("/plex/Record", vec![])));
+    }
+
+    #[test]
+    fn test_command_from_client() {
+        let (server, client, addr) = server();
+        client.send_to(&OscMessage::new("/plex/Record", vec![]).encode(), addr).unwrap();
+        assert_eq!(wait_for_input(&server), Some(OscInput::Command(Commands::Record)));
+
+        client.send_to(&OscMessage::new("/plex/ReplaceStart", vec![OscArg::Int(0)]).encode(), addr).unwrap();
+        assert_eq!(wait_for_input(&server), Some(OscInput::Command(Commands::ReplaceStop)));
+    }
+
+    #[test]
+    fn test_param_from_client() {
+        let (server, client, addr) = server();
+        client.send_to(&OscMessage::new("/plex/param/drylevel", vec![OscArg::Float(0.25)]).encode(), addr).unwrap();
+        assert_eq!(wait_for_input(&server), Some(OscInput::Param(1, 0.25)));
+    }
+
+    #[test]
+    fn test_publish_to_subscriber() {
+        let (server, client, addr) = server();
+        client.send_to(&OscMessage::new("/plex/subscribe", vec![]).encode(), addr).unwrap();
+
+        let mut buf = [0u8; 1024];
+        for _ in 0..50 {
+            server.publish(Telemetry {
+                state: LooperState::Playing,
+                play_position: 10,
+                loop_length: 100,
+                position: BarBeatTick::default(),
+                bpm: 120.,
+                bars: 1.,
+            });
+            if let Ok((len, _)) = client.recv_from(&mut buf) {
+                let message = OscMessage::decode(&buf[..len]).unwrap();
+                if message.addr == "/plex/state" {
+                    assert_eq!(message.args, vec![OscArg::Str("Playing".to_string())]);
+                    return;
+                }
+            }
+        }
+        panic!("no state received");
+    }
+}
@@ -1,85 +1,302 @@
 // handling of audio
-pub type SamplePair = (f32, f32);
+// samples are stored interleaved, a frame holds one sample for every channel
 
+/// the most channels a loop can have
+pub const MAX_CHANNELS: usize = 8;
+
+#[derive(Clone)]
 pub struct RecordingBuffer {
-    pub buffer: Vec<SamplePair>,
-    start_position: usize,
+    pub buffer: Vec<f32>,
+    channels: usize,
 }
 
 const INITIAL_SIZE: usize = 102400;
+// what is left of the loop when overdubbing with full feedback
+const WET_MULT: f32 = 0.98;
 
 impl RecordingBuffer {
     pub fn new() -> RecordingBuffer {
         RecordingBuffer::with_size(INITIAL_SIZE)
     }
 
-    // create a new (empty) buffer with *size* samples
+    // create a new (empty) stereo buffer with *size* frames
     pub fn with_size(size: usize) -> RecordingBuffer {
-        let mut buffer = Vec::with_capacity(size);
-        for _ in 0..size {
-            buffer.push((0.0, 0.0));
-        }
-        RecordingBuffer { buffer, start_position: 0 }
+        RecordingBuffer::with_channels(size, 2)
     }
 
-    /// return the length of the recording buffer
-    ///
-    /// ```
-    /// let b = recording_buffer::RecordingBuffer::with_size(16);
-    /// assert_eq!(b.length(), 16);
-    /// ```
+    // create a new (empty) buffer with *size* frames of *channels* samples
+    pub fn with_channels(size: usize, channels: usize) -> RecordingBuffer {
+        let channels = channels.clamp(1, MAX_CHANNELS);
+        RecordingBuffer { buffer: vec![0.0; size * channels], channels }
+    }
+
+    /// change the number of channels of a loop that is recorded again, the samples aren't converted and
+    /// the memory is kept
+    pub fn set_channels(&mut self, channels: usize) {
+        self.channels = channels.clamp(1, MAX_CHANNELS);
+        let whole = self.buffer.len() - self.buffer.len() % self.channels;
+        self.buffer.truncate(whole);
+    }
+
+    /// return the number of channels in a frame
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    /// return the length of the recording buffer in frames
     pub fn length(&self) -> usize {
-        self.buffer.len()
+        self.buffer.len() / self.channels
     }
 
-    /// insert an empty slice of size at position
+    /// insert an empty slice of size frames at position
     ///
-    /// ```
-    /// let b = recording_buffer::RecordingBuffer::with_size(16);
-    /// for _ in 0..16 {
-    ///   b.push(1.0, 1.0);
-    /// }
-    /// b.insert_empty(2, 4);
-    /// assert_eq!(b.length(), 20);
-    /// assert_eq!(b[2], (1.0, 1.0));
-    /// ```
+    /// the frames after *at* are moved up in place, it only allocates when the buffer has to grow
     pub fn insert_empty(&mut self, at: usize, size: usize)  {
-        let mut ins_buf: Vec<SamplePair> = Vec::with_capacity(size);
-        for _ in 0..size {
-            ins_buf.push((0.0, 0.0));
+        let at = (at * self.channels).min(self.buffer.len());
+        let count = size * self.channels;
+        let end = self.buffer.len();
+        self.buffer.resize(end + count, 0.0);
+        self.buffer.copy_within(at..end, at + count);
+        for sample in self.buffer[at..at + count].iter_mut() {
+            *sample = 0.0;
+        }
+    }
+
+    /// remove *size* frames at position *at*
+    pub fn remove(&mut self, at: usize, size: usize) {
+        let start = (at * self.channels).min(self.buffer.len());
+        let end = ((at + size) * self.channels).min(self.buffer.len());
+        self.buffer.drain(start..end);
+    }
+
+    /// Get the frame at index idx
+    pub fn get(&self, idx: usize) -> Option<&[f32]> {
+        let start = idx * self.channels;
+        self.buffer.get(start..start + self.channels)
+    }
+
+    /// Get the frame at index idx for changing it
+    pub fn get_mut(&mut self, idx: usize) -> Option<&mut [f32]> {
+        let start = idx * self.channels;
+        self.buffer.get_mut(start..start + self.channels)
+    }
+
+    /// Add a frame to the end of the buffer
+    pub fn push(&mut self, frame: &[f32]) {
+        self.buffer.extend_from_slice(&frame[..self.channels]);
+    }
+
+    /// Overwrite a frame with a new one
+    pub fn overwrite(&mut self, idx: usize, frame: &[f32]) {
+        let start = idx * self.channels;
+        self.buffer[start..start + self.channels].copy_from_slice(&frame[..self.channels]);
+    }
+
+    /// Overdub a frame at a specific index
+    pub fn overdub(&mut self, idx: usize, frame: &[f32], feedback: f32) {
+        if let Some(old) = self.get_mut(idx) {
+            for (sample, sample_in) in old.iter_mut().zip(frame) {
+                *sample = (*sample * WET_MULT) * feedback + sample_in;
+            }
+        }
+    }
+
+    /// Reduce the frame at a specific index by the feedback amount
+    pub fn decay(&mut self, idx: usize, feedback: f32) {
+        if let Some(old) = self.get_mut(idx) {
+            for sample in old.iter_mut() {
+                *sample *= feedback;
+            }
+        }
+    }
+
+    /// the frames starting at *idx* as far as *out* reaches, zeros past the end
+    pub fn read_frames(&self, idx: usize, out: &mut [f32]) {
+        let start = (idx * self.channels).min(self.buffer.len());
+        let available = (self.buffer.len() - start).min(out.len());
+        out[..available].copy_from_slice(&self.buffer[start..start + available]);
+        for sample in out[available..].iter_mut() {
+            *sample = 0.0;
+        }
+    }
+
+    /// overwrite the frames starting at *idx*, frames past the end are left out
+    pub fn overwrite_frames(&mut self, idx: usize, frames: &[f32]) {
+        let start = (idx * self.channels).min(self.buffer.len());
+        let count = (self.buffer.len() - start).min(frames.len());
+        self.buffer[start..start + count].copy_from_slice(&frames[..count]);
+    }
+
+    /// add whole frames to the end of the buffer
+    pub fn push_frames(&mut self, frames: &[f32]) {
+        let count = frames.len() - frames.len() % self.channels;
+        self.buffer.extend_from_slice(&frames[..count]);
+    }
+
+    /// overdub the frames starting at *idx*, frames past the end are left out
+    pub fn overdub_frames(&mut self, idx: usize, frames: &[f32], feedback: f32) {
+        let start = (idx * self.channels).min(self.buffer.len());
+        let count = (self.buffer.len() - start).min(frames.len());
+        for (sample, sample_in) in self.buffer[start..start + count].iter_mut().zip(&frames[..count]) {
+            *sample = (*sample * WET_MULT) * feedback + sample_in;
         }
-        println!("len before: {}", self.buffer.len());
+    }
 
-        self.buffer.splice(at..at, ins_buf.iter().cloned());
-        println!("len after: {}", self.buffer.len());
+    /// reduce *count* frames starting at *idx* by the feedback amount
+    pub fn decay_frames(&mut self, idx: usize, count: usize, feedback: f32) {
+        let start = (idx * self.channels).min(self.buffer.len());
+        let end = ((idx + count) * self.channels).min(self.buffer.len());
+        for sample in self.buffer[start..end].iter_mut() {
+            *sample *= feedback;
+        }
     }
 
-    /// Get the SamplePair at index idx
-    pub fn get(&self, idx: usize) -> Option<&SamplePair>{
-        self.buffer.get(idx)
+    /// remove every frame, the memory is kept for what comes next
+    pub fn clear(&mut self) {
+        self.buffer.clear();
     }
 
-    /// Add a sample pair to the end of the buffer
-    pub fn push(&mut self, samples: SamplePair) {
-        self.buffer.push(samples);
+    /// make this a copy of the first *length* frames of *other*, it only allocates when they are more than
+    /// any copy before
+    pub fn copy_from(&mut self, other: &RecordingBuffer, length: usize) {
+        self.channels = other.channels;
+        self.buffer.clear();
+        self.buffer.extend_from_slice(&other.buffer[..(length * other.channels).min(other.buffer.len())]);
     }
 
-    /// Overwrite a sample pair with a new one
-    pub fn overwrite(&mut self, idx: usize, sample : SamplePair) {
-        self.buffer[idx] = sample;
+    /// Rearrange the subdivisions of every cycle within the first *length* frames,
+    /// subdivision order[n] ends up in place of subdivision n
+    ///
+    /// the subdivisions are swapped in place, so the loop isn't copied
+    pub fn reorder(&mut self, length: usize, cycle_len: usize, division_len: usize, order: &[usize]) {
+        if cycle_len == 0 || division_len == 0 || order.iter().any(|&other| other >= order.len()) {
+            return;
+        }
+        let division_samples = division_len * self.channels;
+        let mut cycle_start = 0;
+        while cycle_start + cycle_len <= length {
+            let start = cycle_start * self.channels;
+            let end = start + order.len() * division_samples;
+            if end > self.buffer.len() {
+                break;
+            }
+            let divisions = &mut self.buffer[start..end];
+            // every cycle of the permutation is followed once, from its first subdivision
+            for first in 0..order.len() {
+                if !first_in_cycle(order, first) {
+                    continue;
+                }
+                let mut to = first;
+                while order[to] != first {
+                    let from = order[to];
+                    swap_divisions(divisions, to, from, division_samples);
+                    to = from;
+                }
+            }
+            cycle_start += cycle_len;
+        }
     }
 
-    /// Overdub a value at a specific index
-    pub fn overdub(&mut self, idx: usize, sample: SamplePair, feedback: f32) {
-        const WET_MULT: f32 = 0.98;
-        let (left_in, right_in) = sample;
-        if let Some((left, right)) = self.buffer.get_mut(idx) {
-            *left = (*left * WET_MULT) * feedback + left_in;
-            *right = (*right * WET_MULT) * feedback + right_in;
+}
 
+/// is *first* the lowest subdivision of its cycle in the permutation *order*
+fn first_in_cycle(order: &[usize], first: usize) -> bool {
+    let mut n = order[first];
+    for _ in 0..order.len() {
+        if n == first {
+            return true;
+        }
+        if n < first {
+            return false;
         }
+        n = order[n];
     }
+    // not a permutation
+    false
+}
 
+/// swap subdivisions *a* and *b* of *size* samples
+fn swap_divisions(divisions: &mut [f32], a: usize, b: usize, size: usize) {
+    let (low, high) = (a.min(b), a.max(b));
+    let (before, after) = divisions.split_at_mut(high * size);
+    before[low * size..(low + 1) * size].swap_with_slice(&mut after[..size]);
+}
+
+/// where the frames of a loop are kept, in memory (RecordingBuffer) or on disk (DiskStore)
+pub trait LoopStore {
+    fn channels(&self) -> usize;
+    fn length(&self) -> usize;
+    fn get(&self, idx: usize) -> Option<&[f32]>;
+    fn get_mut(&mut self, idx: usize) -> Option<&mut [f32]>;
+    fn push(&mut self, frame: &[f32]);
+    fn overwrite(&mut self, idx: usize, frame: &[f32]);
+    fn overdub(&mut self, idx: usize, frame: &[f32], feedback: f32);
+    fn decay(&mut self, idx: usize, feedback: f32);
+    fn insert_empty(&mut self, at: usize, size: usize);
+    fn remove(&mut self, at: usize, size: usize);
+
+    // the same for a run of frames, stores that can do better than frame by frame override these
+
+    fn read_frames(&self, idx: usize, out: &mut [f32]) {
+        let channels = self.channels();
+        for (n, frame) in out.chunks_mut(channels).enumerate() {
+            match self.get(idx + n) {
+                Some(old) => frame.copy_from_slice(&old[..frame.len()]),
+                None => for sample in frame.iter_mut() { *sample = 0.0 },
+            }
+        }
+    }
+    fn overwrite_frames(&mut self, idx: usize, frames: &[f32]) {
+        let channels = self.channels();
+        for (n, frame) in frames.chunks(channels).enumerate() {
+            if let Some(old) = self.get_mut(idx + n) {
+                old.copy_from_slice(frame);
+            }
+        }
+    }
+    fn push_frames(&mut self, frames: &[f32]) {
+        let channels = self.channels();
+        for frame in frames.chunks(channels).filter(|frame| frame.len() == channels) {
+            self.push(frame);
+        }
+    }
+    fn overdub_frames(&mut self, idx: usize, frames: &[f32], feedback: f32) {
+        let channels = self.channels();
+        for (n, frame) in frames.chunks(channels).enumerate() {
+            self.overdub(idx + n, frame, feedback);
+        }
+    }
+    fn decay_frames(&mut self, idx: usize, count: usize, feedback: f32) {
+        for n in 0..count {
+            self.decay(idx + n, feedback);
+        }
+    }
+}
+
+impl LoopStore for RecordingBuffer {
+    fn channels(&self) -> usize { RecordingBuffer::channels(self) }
+    fn length(&self) -> usize { RecordingBuffer::length(self) }
+    fn get(&self, idx: usize) -> Option<&[f32]> { RecordingBuffer::get(self, idx) }
+    fn get_mut(&mut self, idx: usize) -> Option<&mut [f32]> { RecordingBuffer::get_mut(self, idx) }
+    fn push(&mut self, frame: &[f32]) { RecordingBuffer::push(self, frame) }
+    fn overwrite(&mut self, idx: usize, frame: &[f32]) { RecordingBuffer::overwrite(self, idx, frame) }
+    fn overdub(&mut self, idx: usize, frame: &[f32], feedback: f32) {
+        RecordingBuffer::overdub(self, idx, frame, feedback)
+    }
+    fn decay(&mut self, idx: usize, feedback: f32) { RecordingBuffer::decay(self, idx, feedback) }
+    fn insert_empty(&mut self, at: usize, size: usize) { RecordingBuffer::insert_empty(self, at, size) }
+    fn remove(&mut self, at: usize, size: usize) { RecordingBuffer::remove(self, at, size) }
+    fn read_frames(&self, idx: usize, out: &mut [f32]) { RecordingBuffer::read_frames(self, idx, out) }
+    fn overwrite_frames(&mut self, idx: usize, frames: &[f32]) {
+        RecordingBuffer::overwrite_frames(self, idx, frames)
+    }
+    fn push_frames(&mut self, frames: &[f32]) { RecordingBuffer::push_frames(self, frames) }
+    fn overdub_frames(&mut self, idx: usize, frames: &[f32], feedback: f32) {
+        RecordingBuffer::overdub_frames(self, idx, frames, feedback)
+    }
+    fn decay_frames(&mut self, idx: usize, count: usize, feedback: f32) {
+        RecordingBuffer::decay_frames(self, idx, count, feedback)
+    }
 }
 
 impl Default for RecordingBuffer {
@@ -96,20 +313,23 @@ mod tests {
     fn build_new_buffer() {
         let b = RecordingBuffer::new();
 
-        assert_eq!(b.buffer.len(), 102400);
+        assert_eq!(b.length(), 102400);
     }
 
     #[test]
     fn build_sized_buffer() {
         let b = RecordingBuffer::with_size(1024);
 
-        assert_eq!(b.buffer.len(), 1024);
+        assert_eq!(b.length(), 1024);
     }
 
     #[test]
     fn test_len() {
         let b = RecordingBuffer::with_size(1024);
         assert_eq!(b.length(), 1024);
+        let b = RecordingBuffer::with_channels(16, 4);
+        assert_eq!(b.length(), 16);
+        assert_eq!(b.buffer.len(), 64);
     }
 
 
@@ -118,26 +338,145 @@ mod tests {
         let mut b = RecordingBuffer::with_size(16);
         // add another 16 samples
         for _ in 0..16 {
-            b.push((1.0, 1.0));
+            b.push(&[1.0, 1.0]);
         }
         b.insert_empty(20, 4);
         assert_eq!(b.length(), 36);
-        assert_eq!(Some(&(1.0, 1.)), b.get(19));
-        assert_eq!(Some(&(0., 0.)), b.get(20));
+        assert_eq!(Some(&[1.0, 1.][..]), b.get(19));
+        assert_eq!(Some(&[0., 0.][..]), b.get(20));
+        assert_eq!(Some(&[0., 0.][..]), b.get(23));
+        assert_eq!(Some(&[1.0, 1.][..]), b.get(24));
+        assert_eq!(Some(&[1.0, 1.][..]), b.get(35));
+        // in the middle of the old frames
+        b.insert_empty(2, 4);
+        assert_eq!(b.length(), 40);
+        assert_eq!(Some(&[0., 0.][..]), b.get(2));
+        assert_eq!(Some(&[0., 0.][..]), b.get(5));
+        assert_eq!(Some(&[1.0, 1.][..]), b.get(20));
     }
 
     #[test]
     fn test_overwrite() {
         let mut b = RecordingBuffer::with_size(16);
-        b.overwrite(1, (0.5, 0.5));
-        assert_eq!(Some(&(0.5, 0.5)), b.get(1));
+        b.overwrite(1, &[0.5, 0.5]);
+        assert_eq!(Some(&[0.5, 0.5][..]), b.get(1));
     }
 
     #[test]
     fn test_overdub() {
         let mut b = RecordingBuffer::with_size(16);
-        b.overwrite(1, (0.5, 0.5));
-        b.overdub(1, (0.2, -0.2), 1.0);
-        assert_eq!(Some(&(0.69, 0.29000002)), b.get(1));
+        b.overwrite(1, &[0.5, 0.5]);
+        b.overdub(1, &[0.2, -0.2], 1.0);
+        assert_eq!(Some(&[0.69, 0.29000002][..]), b.get(1));
+    }
+
+    #[test]
+    fn test_decay() {
+        let mut b = RecordingBuffer::with_size(16);
+        b.overwrite(1, &[0.5, -0.5]);
+        b.decay(1, 0.5);
+        assert_eq!(Some(&[0.25, -0.25][..]), b.get(1));
+        b.decay(20, 0.5);
+        assert_eq!(b.length(), 16);
+    }
+
+    #[test]
+    fn test_frames_match_single_frames() {
+        let mut single = RecordingBuffer::with_size(0);
+        for i in 0..16 {
+            single.push(&[i as f32, -(i as f32)]);
+        }
+        let mut block = single.clone();
+        let input: Vec<f32> = (0..12).map(|i| i as f32 * 0.1).collect();
+        // the last frames are past the end
+        for (n, frame) in input.chunks(2).enumerate() {
+            single.overdub(12 + n, frame, 0.5);
+        }
+        block.overdub_frames(12, &input, 0.5);
+        assert_eq!(single.buffer, block.buffer);
+        for n in 0..4 {
+            single.decay(2 + n, 0.5);
+        }
+        block.decay_frames(2, 4, 0.5);
+        assert_eq!(single.buffer, block.buffer);
+        block.overwrite_frames(14, &input);
+        assert_eq!(block.length(), 16);
+        assert_eq!(block.get(15), Some(&[0.2, 0.3][..]));
+        block.push_frames(&input[..5]);
+        assert_eq!(block.length(), 18);
+        let mut out = [1.0; 6];
+        block.read_frames(17, &mut out);
+        assert_eq!(out, [0.2, 0.3, 0.0, 0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_copy_from() {
+        let mut b = RecordingBuffer::with_size(16);
+        let mut other = RecordingBuffer::with_channels(0, 1);
+        other.push(&[0.5]);
+        other.push(&[1.0]);
+        let capacity = b.buffer.capacity();
+        b.copy_from(&other, 1);
+        assert_eq!(b.length(), 1);
+        assert_eq!(b.get(0), Some(&[0.5][..]));
+        assert_eq!(b.buffer.capacity(), capacity);
+    }
+
+    #[test]
+    fn test_reorder() {
+        let mut b = RecordingBuffer::with_size(0);
+        for i in 0..8 {
+            b.push(&[i as f32, i as f32]);
+        }
+        // two cycles of two subdivisions each
+        b.reorder(8, 4, 2, &[1, 0]);
+        assert_eq!(Some(&[2.0, 2.0][..]), b.get(0));
+        assert_eq!(Some(&[3.0, 3.0][..]), b.get(1));
+        assert_eq!(Some(&[0.0, 0.0][..]), b.get(2));
+        assert_eq!(Some(&[6.0, 6.0][..]), b.get(4));
+    }
+
+    #[test]
+    fn test_reorder_cycles() {
+        // order[n] is played in place of n: 2 goes to 0, 0 to 1, 1 to 2, and 3 stays
+        let mut b = RecordingBuffer::with_channels(0, 1);
+        for i in 0..4 {
+            b.push(&[i as f32]);
+        }
+        b.reorder(4, 4, 1, &[2, 0, 1, 3]);
+        assert_eq!(b.buffer, vec![2.0, 0.0, 1.0, 3.0]);
+        // not a permutation, nothing changes
+        b.reorder(4, 4, 1, &[0, 0, 1, 2]);
+        assert_eq!(b.buffer, vec![2.0, 0.0, 1.0, 3.0]);
+    }
+
+    #[test]
+    fn test_mono() {
+        let mut b = RecordingBuffer::with_channels(4, 1);
+        assert_eq!(b.length(), 4);
+        assert_eq!(b.buffer.len(), 4);
+        b.overwrite(2, &[0.5]);
+        assert_eq!(Some(&[0.5][..]), b.get(2));
+        b.insert_empty(2, 2);
+        assert_eq!(b.length(), 6);
+        assert_eq!(Some(&[0.5][..]), b.get(4));
+    }
+
+    #[test]
+    fn test_set_channels() {
+        let mut b = RecordingBuffer::with_channels(5, 2);
+        let capacity = b.buffer.capacity();
+        b.set_channels(3);
+        assert_eq!((b.channels(), b.length()), (3, 3));
+        assert_eq!(b.buffer.capacity(), capacity);
+    }
+
+    #[test]
+    fn test_quad() {
+        let mut b = RecordingBuffer::with_channels(4, 4);
+        b.push(&[1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(b.length(), 5);
+        assert_eq!(Some(&[1.0, 2.0, 3.0, 4.0][..]), b.get(4));
+        assert_eq!(None, b.get(5));
     }
 }
\ No newline at end of file
@@ -0,0 +1,131 @@
+// sample rate conversion of recorded loops, used when the host changes the sample rate
+//
+// windowed sinc interpolation (Blackman window), the loop is treated as circular so the
+// loop point stays seamless after conversion
+
+use std::f64::consts::PI;
+
+use recording_buffer::{RecordingBuffer, MAX_CHANNELS};
+
+// zero crossings of the sinc on each side of a sample, more is sharper but slower
+const ZERO_CROSSINGS: usize = 32;
+
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-9 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+// Blackman window for -1 <= x <= 1
+fn window(x: f64) -> f64 {
+    if x.abs() >= 1.0 {
+        0.0
+    } else {
+        0.42 + 0.5 * (PI * x).cos() + 0.08 * (2.0 * PI * x).cos()
+    }
+}
+
+/// the length of *length* samples after converting from *from* to *to* Hz
+pub fn scale(length: usize, from: f64, to: f64) -> usize {
+    (length as f64 * to / from).round() as usize
+}
+
+/// convert the first *length* frames of *buffer* from *from* to *to* Hz
+pub fn resample(buffer: &RecordingBuffer, length: usize, from: f64, to: f64) -> RecordingBuffer {
+    let channels = buffer.channels();
+    let length = length.min(buffer.length());
+    let new_length = scale(length, from, to);
+    let mut resampled = RecordingBuffer::with_channels(new_length.max(buffer.length()), channels);
+    if length == 0 {
+        return resampled;
+    }
+
+    let ratio = to / from;
+    // when going down, filter out what doesn't fit below the new nyquist frequency
+    let cutoff = ratio.min(1.0);
+    let half_width = ZERO_CROSSINGS as f64 / cutoff;
+
+    let mut frame = [0.0; MAX_CHANNELS];
+    for n in 0..new_length {
+        let t = n as f64 / ratio;
+        let first = (t - half_width).ceil() as i64;
+        let last = (t + half_width).floor() as i64;
+        for sample in frame.iter_mut() {
+            *sample = 0.0;
+        }
+        for k in first..last + 1 {
+            let distance = t - k as f64;
+            let weight = cutoff * sinc(cutoff * distance) * window(distance / half_width);
+            let index = ((k % length as i64 + length as i64) % length as i64) as usize;
+            if let Some(old) = buffer.get(index) {
+                for (sample, sample_old) in frame.iter_mut().zip(old) {
+                    *sample += weight * *sample_old as f64;
+                }
+            }
+        }
+        let mut new_frame = [0.0f32; MAX_CHANNELS];
+        for (sample, sample_new) in new_frame.iter_mut().zip(frame.iter()) {
+            *sample = *sample_new as f32;
+        }
+        resampled.overwrite(n, &new_frame[..channels]);
+    }
+    resampled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(length: usize, period: usize) -> RecordingBuffer {
+        let mut buffer = RecordingBuffer::with_channels(length, 1);
+        for n in 0..length {
+            let value = (2.0 * PI * n as f64 / period as f64).sin() as f32;
+            buffer.overwrite(n, &[value]);
+        }
+        buffer
+    }
+
+    #[test]
+    fn test_scale() {
+        assert_eq!(scale(48000, 48000., 96000.), 96000);
+        assert_eq!(scale(441, 44100., 48000.), 480);
+    }
+
+    #[test]
+    fn test_same_rate() {
+        let buffer = sine(1000, 100);
+        let resampled = resample(&buffer, 1000, 48000., 48000.);
+        for n in 0..1000 {
+            assert!((resampled.get(n).unwrap()[0] - buffer.get(n).unwrap()[0]).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_up() {
+        // a sine with a period of 100 samples has a period of 200 samples at twice the rate
+        let buffer = sine(1000, 100);
+        let resampled = resample(&buffer, 1000, 24000., 48000.);
+        let expected = sine(2000, 200);
+        for n in 0..2000 {
+            assert!((resampled.get(n).unwrap()[0] - expected.get(n).unwrap()[0]).abs() < 1e-2);
+        }
+    }
+
+    #[test]
+    fn test_down_stereo() {
+        let mut buffer = RecordingBuffer::with_channels(1000, 2);
+        for n in 0..1000 {
+            let value = (2.0 * PI * n as f64 / 100.).sin() as f32;
+            buffer.overwrite(n, &[value, -value]);
+        }
+        let resampled = resample(&buffer, 1000, 96000., 48000.);
+        let expected = sine(500, 50);
+        for n in 0..500 {
+            let frame = resampled.get(n).unwrap();
+            assert!((frame[0] - expected.get(n).unwrap()[0]).abs() < 1e-2);
+            assert!((frame[1] + expected.get(n).unwrap()[0]).abs() < 1e-2);
+        }
+    }
+}
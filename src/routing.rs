@@ -0,0 +1,157 @@
+// routing of the host inputs into the channels of the loop
+
+use std::fmt;
+
+use recording_buffer::MAX_CHANNELS;
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum RoutingMode {
+    // input n is recorded to loop channel n, missing inputs are repeated (mono input to both sides)
+    #[default]
+    Direct,
+    // the first input is recorded to all loop channels
+    FirstToAll,
+    // the sum of all inputs is recorded to all loop channels
+    SumToAll,
+}
+
+impl From<usize> for RoutingMode {
+    fn from(val: usize) -> RoutingMode {
+        match val {
+            1 => RoutingMode::FirstToAll,
+            2 => RoutingMode::SumToAll,
+            _ => RoutingMode::Direct,
+        }
+    }
+}
+
+impl fmt::Display for RoutingMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let printable = match *self {
+            RoutingMode::Direct => "Direct",
+            RoutingMode::FirstToAll => "First to All",
+            RoutingMode::SumToAll => "Sum to All",
+        };
+        write!(f, "{}", printable)
+    }
+}
+
+pub struct Routing {
+    pub mode: RoutingMode,
+    inputs: usize,
+    channels: usize,
+    // gain of input i into loop channel c is at matrix[c * inputs + i]
+    // it has room for every layout, so a routing is made without allocating when the host inputs change
+    matrix: [f32; MAX_CHANNELS * MAX_CHANNELS],
+}
+
+impl Default for Routing {
+    fn default() -> Routing {
+        Routing::new(RoutingMode::Direct, 0, 0)
+    }
+}
+
+impl Routing {
+    pub fn new(mode: RoutingMode, inputs: usize, channels: usize) -> Routing {
+        let inputs = inputs.min(MAX_CHANNELS);
+        let channels = channels.min(MAX_CHANNELS);
+        let mut routing = Routing { mode, inputs, channels, matrix: [0.0; MAX_CHANNELS * MAX_CHANNELS] };
+        if inputs == 0 {
+            return routing;
+        }
+        for c in 0..channels {
+            match mode {
+                RoutingMode::Direct => {
+                    // more inputs than channels are mixed down, fewer inputs are repeated
+                    let sources = (0..inputs).filter(|i| i % channels == c).count();
+                    if sources == 0 {
+                        routing.set_gain(c, c % inputs, 1.0);
+                    }
+                    for i in (0..inputs).filter(|i| i % channels == c) {
+                        routing.set_gain(c, i, 1.0 / sources as f32);
+                    }
+                }
+                RoutingMode::FirstToAll => routing.set_gain(c, 0, 1.0),
+                RoutingMode::SumToAll => {
+                    for i in 0..inputs {
+                        routing.set_gain(c, i, 1.0 / inputs as f32);
+                    }
+                }
+            }
+        }
+        routing
+    }
+
+    pub fn inputs(&self) -> usize {
+        self.inputs
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    /// does the matrix route *inputs* host inputs into *channels* loop channels?
+    pub fn fits(&self, inputs: usize, channels: usize) -> bool {
+        self.inputs == inputs && self.channels == channels
+    }
+
+    /// set the gain of *input* into loop *channel*
+    pub fn set_gain(&mut self, channel: usize, input: usize, gain: f32) {
+        if channel < self.channels && input < self.inputs {
+            self.matrix[channel * self.inputs + input] = gain;
+        }
+    }
+
+    /// mix the *input* samples into the loop *frame*
+    pub fn route(&self, input: &[f32], frame: &mut [f32]) {
+        for (c, sample) in frame.iter_mut().enumerate().take(self.channels) {
+            let gains = &self.matrix[c * self.inputs..(c + 1) * self.inputs];
+            *sample = gains.iter().zip(input).map(|(gain, sample_in)| gain * sample_in).sum();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_direct_stereo() {
+        let r = Routing::new(RoutingMode::Direct, 2, 2);
+        let mut frame = [0.0; 2];
+        r.route(&[0.5, -0.5], &mut frame);
+        assert_eq!(frame, [0.5, -0.5]);
+    }
+
+    #[test]
+    fn test_direct_mono_to_stereo() {
+        let r = Routing::new(RoutingMode::Direct, 1, 2);
+        let mut frame = [0.0; 2];
+        r.route(&[0.5], &mut frame);
+        assert_eq!(frame, [0.5, 0.5]);
+    }
+
+    #[test]
+    fn test_direct_stereo_to_mono() {
+        let r = Routing::new(RoutingMode::Direct, 2, 1);
+        let mut frame = [0.0; 1];
+        r.route(&[0.5, 0.25], &mut frame);
+        assert_eq!(frame, [0.375]);
+    }
+
+    #[test]
+    fn test_first_to_all() {
+        let r = Routing::new(RoutingMode::FirstToAll, 2, 4);
+        let mut frame = [0.0; 4];
+        r.route(&[0.5, 0.25], &mut frame);
+        assert_eq!(frame, [0.5, 0.5, 0.5, 0.5]);
+    }
+
+    #[test]
+    fn test_no_inputs() {
+        let r = Routing::new(RoutingMode::Direct, 0, 2);
+        let mut frame = [1.0; 2];
+        r.route(&[], &mut frame);
+        assert_eq!(frame, [0.0, 0.0]);
+    }
+}
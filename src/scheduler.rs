@@ -0,0 +1,100 @@
+// commands waiting for a sync point
+//
+// a synced command is scheduled for the exact sample it has to happen at (counted since the plugin started),
+// the engine splits its blocks at the sync points, so a sync point can't be missed
+
+use looper_fsm::Commands;
+
+// more than enough, there are at most a start and a stop waiting
+const CAPACITY: usize = 16;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Pending {
+    pub at: usize,
+    pub command: Commands,
+}
+
+pub struct Scheduler {
+    // sorted by time
+    pending: Vec<Pending>,
+}
+
+impl Default for Scheduler {
+    fn default() -> Scheduler {
+        Scheduler { pending: Vec::with_capacity(CAPACITY) }
+    }
+}
+
+impl Scheduler {
+    /// run *command* at sample *at*
+    pub fn schedule(&mut self, at: usize, command: Commands) {
+        if self.pending.len() == CAPACITY {
+            info!("scheduler full, dropping {}", command);
+            return;
+        }
+        let index = self.pending.iter().position(|p| p.at > at).unwrap_or(self.pending.len());
+        self.pending.insert(index, Pending { at, command });
+    }
+
+    /// the next command that is due at sample *now*
+    pub fn due(&mut self, now: usize) -> Option<Commands> {
+        if self.pending.first().is_some_and(|p| p.at <= now) {
+            Some(self.pending.remove(0).command)
+        } else {
+            None
+        }
+    }
+
+    /// when the next command is due, if one is waiting
+    pub fn next_time(&self) -> Option<usize> {
+        self.pending.first().map(|p| p.at)
+    }
+
+    /// when *command* is going to run, if it is waiting
+    pub fn time_of(&self, command: Commands) -> Option<usize> {
+        self.pending.iter().find(|p| p.command == command).map(|p| p.at)
+    }
+
+    pub fn is_pending(&self, command: Commands) -> bool {
+        self.time_of(command).is_some()
+    }
+
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+
+    /// keep the commands the same time away from *now* after a change of sample rate
+    pub fn rescale(&mut self, now: usize, from: f64, to: f64) {
+        for pending in self.pending.iter_mut() {
+            let distance = pending.at.saturating_sub(now) as f64 * to / from;
+            pending.at = now + distance.round() as usize;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_order() {
+        let mut s = Scheduler::default();
+        s.schedule(200, Commands::ReplaceStop);
+        s.schedule(100, Commands::ReplaceStart);
+        assert_eq!(s.time_of(Commands::ReplaceStop), Some(200));
+        assert_eq!(s.due(50), None);
+        // a command that was missed is still run
+        assert_eq!(s.due(150), Some(Commands::ReplaceStart));
+        assert_eq!(s.due(150), None);
+        assert_eq!(s.due(200), Some(Commands::ReplaceStop));
+        assert!(!s.is_pending(Commands::ReplaceStop));
+    }
+
+    #[test]
+    fn test_rescale() {
+        let mut s = Scheduler::default();
+        s.schedule(1100, Commands::InsertStop);
+        s.rescale(1000, 48000., 96000.);
+        assert_eq!(s.time_of(Commands::InsertStop), Some(1200));
+    }
+}
@@ -0,0 +1,97 @@
+// settings read from plexlooper.conf in the PlexLooper folder, one `key = value` per line:
+//
+//     # port of the OSC server, 0 (the default) turns OSC off
+//     osc_port = 9000
+//     # the address the OSC server listens on, 0.0.0.0 lets in every host on the network
+//     osc_address = 127.0.0.1
+//     # keep loops on disk instead of in memory, for very long loops
+//     disk_loops = false
+//     # how long a note is held for a long press, and how soon a second press makes a double tap (in samples)
+//     long_press = 24000
+//     double_tap = 12000
+
+use std::fs::File;
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
+
+use gesture::{DEFAULT_LONG_PRESS, DEFAULT_DOUBLE_TAP};
+
+pub struct Settings {
+    pub osc_port: u16,
+    pub osc_address: IpAddr,
+    pub disk_loops: bool,
+    pub long_press: usize,
+    pub double_tap: usize,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            osc_port: 0,
+            // only this computer, every instance would take commands from the whole network otherwise
+            osc_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            disk_loops: false,
+            long_press: DEFAULT_LONG_PRESS,
+            double_tap: DEFAULT_DOUBLE_TAP,
+        }
+    }
+}
+
+impl Settings {
+    /// read the settings from *path*, missing settings keep their default
+    pub fn load(path: &Path) -> Settings {
+        let mut text = String::new();
+        match File::open(path).and_then(|mut file| file.read_to_string(&mut text)) {
+            Ok(_) => Settings::parse(&text),
+            Err(_) => Settings::default(),
+        }
+    }
+
+    pub fn parse(text: &str) -> Settings {
+        let mut settings = Settings::default();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap_or("").trim();
+            let value = parts.next().unwrap_or("").trim();
+            let ok = match key {
+                "osc_port" => value.parse().map(|v| settings.osc_port = v).is_ok(),
+                "osc_address" => value.parse().map(|v| settings.osc_address = v).is_ok(),
+                "disk_loops" => value.parse().map(|v| settings.disk_loops = v).is_ok(),
+                "long_press" => value.parse().map(|v| settings.long_press = v).is_ok(),
+                "double_tap" => value.parse().map(|v| settings.double_tap = v).is_ok(),
+                _ => false,
+            };
+            if !ok {
+                info!("skipping setting '{}'", line);
+            }
+        }
+        settings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let settings = Settings::parse("# comment\n osc_port = 8000 \n");
+        assert_eq!(settings.osc_port, 8000);
+        assert!(!settings.disk_loops);
+        assert!(Settings::parse("disk_loops = true").disk_loops);
+        assert_eq!(Settings::parse("long_press = 48000").long_press, 48000);
+        assert_eq!(Settings::parse("osc_address = 0.0.0.0").osc_address, IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)));
+    }
+
+    #[test]
+    fn test_defaults() {
+        let settings = Settings::parse("osc_port = lots\nosc_address = anywhere\nunknown = 1");
+        assert_eq!(settings.osc_port, 0);
+        assert!(settings.osc_address.is_loopback());
+    }
+}
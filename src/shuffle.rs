@@ -0,0 +1,140 @@
+// Shuffle: play the subdivisions of each cycle in a different order
+
+use std::fmt;
+use rand::{Rng, SeedableRng, StdRng};
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum ShuffleMode {
+    #[default]
+    Off,
+    Random,
+    // play the subdivisions back to front
+    Reverse,
+    // swap every pair of subdivisions
+    Pairs,
+}
+
+impl From<usize> for ShuffleMode {
+    fn from(val: usize) -> ShuffleMode {
+        match val {
+            1 => ShuffleMode::Random,
+            2 => ShuffleMode::Reverse,
+            3 => ShuffleMode::Pairs,
+            _ => ShuffleMode::Off,
+        }
+    }
+}
+
+impl fmt::Display for ShuffleMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let printable = match *self {
+            ShuffleMode::Off => "Off",
+            ShuffleMode::Random => "Random",
+            ShuffleMode::Reverse => "Reverse",
+            ShuffleMode::Pairs => "Pairs",
+        };
+        write!(f, "{}", printable)
+    }
+}
+
+#[derive(Default)]
+pub struct Shuffle {
+    pub mode: ShuffleMode,
+    seed: usize,
+    // order[n] is the subdivision we play in place of subdivision n
+    order: Vec<usize>,
+}
+
+impl Shuffle {
+    pub fn set_mode(&mut self, mode: ShuffleMode, divisions: usize) {
+        self.mode = mode;
+        self.generate(divisions);
+    }
+
+    pub fn set_seed(&mut self, seed: usize, divisions: usize) {
+        self.seed = seed;
+        self.generate(divisions);
+    }
+
+    /// build the order of *divisions* subdivisions for the current mode and seed
+    pub fn generate(&mut self, divisions: usize) {
+        let mut order: Vec<usize> = (0..divisions).collect();
+        match self.mode {
+            ShuffleMode::Off => {}
+            ShuffleMode::Random => {
+                let seed: &[usize] = &[self.seed];
+                let mut rng: StdRng = SeedableRng::from_seed(seed);
+                rng.shuffle(&mut order);
+            }
+            ShuffleMode::Reverse => order.reverse(),
+            ShuffleMode::Pairs => {
+                for pair in order.chunks_mut(2) {
+                    pair.reverse();
+                }
+            }
+        }
+        self.order = order;
+    }
+
+    pub fn order(&self) -> &[usize] {
+        &self.order
+    }
+
+    /// map a position in the loop to the position we play instead
+    pub fn index(&self, position: usize, cycle_len: usize, division_len: usize) -> usize {
+        if self.mode == ShuffleMode::Off || division_len == 0 || cycle_len == 0 {
+            return position;
+        }
+        let cycle_start = position - position % cycle_len;
+        let in_cycle = position % cycle_len;
+        let subdivision = in_cycle / division_len;
+        match self.order.get(subdivision) {
+            Some(other) => cycle_start + other * division_len + in_cycle % division_len,
+            // the rest of a cycle that doesn't divide evenly stays in place
+            None => position,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_off_keeps_position() {
+        let s = Shuffle::default();
+        assert_eq!(s.index(123, 100, 10), 123);
+    }
+
+    #[test]
+    fn test_pairs() {
+        let mut s = Shuffle::default();
+        s.set_mode(ShuffleMode::Pairs, 3);
+        assert_eq!(s.order(), &[1, 0, 2]);
+        assert_eq!(s.index(103, 100, 30), 133);
+        // remainder of the cycle
+        assert_eq!(s.index(195, 100, 30), 195);
+    }
+
+    #[test]
+    fn test_reverse() {
+        let mut s = Shuffle::default();
+        s.set_mode(ShuffleMode::Reverse, 4);
+        assert_eq!(s.index(5, 40, 10), 35);
+    }
+
+    #[test]
+    fn test_random_is_reproducible() {
+        let mut a = Shuffle::default();
+        let mut b = Shuffle::default();
+        a.set_seed(42, 16);
+        a.set_mode(ShuffleMode::Random, 16);
+        b.set_seed(42, 16);
+        b.set_mode(ShuffleMode::Random, 16);
+        assert_eq!(a.order(), b.order());
+
+        let mut sorted = a.order().to_vec();
+        sorted.sort();
+        assert_eq!(sorted, (0..16).collect::<Vec<usize>>());
+    }
+}
@@ -0,0 +1,97 @@
+// smoothing of parameter changes, so level changes don't produce zipper noise
+
+/// how long (in ms) it takes a smoothed value to (mostly) reach its target
+const SMOOTHING_TIME: f64 = 20.;
+/// a smoothed value this close to its target has reached it (-120dB)
+const SETTLED: f32 = 1e-6;
+
+pub struct Smoother {
+    current: f32,
+    target: f32,
+    coeff: f32,
+}
+
+impl Smoother {
+    /// create a smoother that starts out at *value*
+    pub fn new(value: f32) -> Smoother {
+        let mut smoother = Smoother { current: value, target: value, coeff: 0. };
+        smoother.set_sample_rate(44100.);
+        smoother
+    }
+
+    /// recalculate the one pole coefficient for a new sample rate
+    pub fn set_sample_rate(&mut self, fs: f64) {
+        let samples = fs / 1000. * SMOOTHING_TIME;
+        self.coeff = (-1.0 / samples).exp() as f32;
+    }
+
+    /// set the value we are gliding towards
+    pub fn set_target(&mut self, target: f32) {
+        self.target = target;
+    }
+
+    /// advance by one sample and return the smoothed value
+    pub fn next(&mut self) -> f32 {
+        self.current = self.target + (self.current - self.target) * self.coeff;
+        self.current
+    }
+
+    /// the smoothed values for a block of *values.len()* samples
+    pub fn fill(&mut self, values: &mut [f32]) {
+        // a value that reached its target stays there, without working out every sample
+        if (self.current - self.target).abs() < SETTLED {
+            self.current = self.target;
+            for value in values.iter_mut() {
+                *value = self.target;
+            }
+            return;
+        }
+        for value in values.iter_mut() {
+            *value = self.next();
+        }
+    }
+}
+
+impl Default for Smoother {
+    fn default() -> Smoother {
+        Smoother::new(0.)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_starts_at_value() {
+        let mut s = Smoother::new(0.5);
+        assert_eq!(s.next(), 0.5);
+    }
+
+    #[test]
+    fn test_glides_to_target() {
+        let mut s = Smoother::new(0.);
+        s.set_sample_rate(1000.);
+        s.set_target(1.);
+        let first = s.next();
+        assert!(first > 0. && first < 1.);
+        for _ in 0..1000 {
+            s.next();
+        }
+        assert!((s.next() - 1.).abs() < 0.0001);
+    }
+
+    #[test]
+    fn test_fill() {
+        let mut s = Smoother::new(0.);
+        s.set_sample_rate(1000.);
+        s.set_target(1.);
+        let mut values = [0.; 400];
+        s.fill(&mut values);
+        assert!(values[0] > 0. && values[0] < values[1]);
+        // settled
+        s.fill(&mut values);
+        assert!(values.iter().all(|value| *value == 1.));
+        assert_eq!(s.next(), 1.);
+    }
+}
@@ -0,0 +1,198 @@
+// the engine's state for a UI, and what a UI asks of the engine
+//
+// the audio thread publishes a snapshot at the end of every block through a triple buffer, so a UI always gets
+// the newest one, and takes the input of the UI at the start of the next block through a lock-free queue. A UI
+// only ever sees snapshots, so it never reads the engine's state while process is changing it
+
+use std::str;
+use std::sync::{Arc, Mutex};
+
+use looper_fsm::{Commands, InsertMode, LooperState};
+use spsc::{self, Consumer, Producer};
+use triple_buffer::{self, Reader, Writer};
+use sync_bus::SyncRole;
+use tempo::BarBeatTick;
+
+// the input of a UI between two blocks
+const QUEUE_SIZE: usize = 64;
+/// the longest loop name a snapshot holds, in bytes
+pub const NAME_LEN: usize = 48;
+
+/// what a UI sends to the engine
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UiInput {
+    Command(Commands),
+    // index of the parameter and its new value
+    Param(usize, f32),
+}
+
+/// the state of the engine after a block
+#[derive(Clone, Copy)]
+pub struct Snapshot {
+    pub state: LooperState,
+    pub play_position: usize,
+    pub loop_length: usize,
+    pub cycle_len: usize,
+    pub position: BarBeatTick,
+    pub seconds: f64,
+    // the play position in seconds, for a loop without a tempo
+    pub cycles: usize,
+    pub total_cycles: usize,
+    pub division: usize,
+    pub bars: f64,
+    pub bpm: f64,
+    pub insert_mode: InsertMode,
+    pub reverse: bool,
+    pub half_speed: bool,
+    pub sync_role: SyncRole,
+    name: [u8; NAME_LEN],
+    // the library loop that is playing, it is copied so the audio thread doesn't allocate
+    name_len: usize,
+}
+
+impl Default for Snapshot {
+    fn default() -> Snapshot {
+        Snapshot {
+            state: LooperState::Stopped,
+            play_position: 0,
+            loop_length: 0,
+            cycle_len: 0,
+            position: BarBeatTick::default(),
+            seconds: 0.,
+            cycles: 0,
+            total_cycles: 0,
+            division: 0,
+            bars: 0.,
+            bpm: 0.,
+            insert_mode: InsertMode::Insert,
+            reverse: false,
+            half_speed: false,
+            sync_role: SyncRole::Off,
+            name: [0; NAME_LEN],
+            name_len: 0,
+        }
+    }
+}
+
+impl Snapshot {
+    /// the name of the library loop that is playing, empty if there is none
+    pub fn loop_name(&self) -> &str {
+        str::from_utf8(&self.name[..self.name_len]).unwrap_or("")
+    }
+
+    /// a name longer than NAME_LEN bytes is cut
+    pub fn set_loop_name(&mut self, name: &str) {
+        let mut len = name.len().min(NAME_LEN);
+        while !name.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.name[..len].copy_from_slice(&name.as_bytes()[..len]);
+        self.name_len = len;
+    }
+}
+
+/// the engine's end of the link to a UI
+pub struct EngineLink {
+    snapshots: Writer<Snapshot>,
+    inputs: Consumer<UiInput>,
+}
+
+impl EngineLink {
+    /// let the UI know the state after a block, never blocks
+    pub fn publish(&self, snapshot: Snapshot) {
+        // a snapshot the UI didn't get to is replaced
+        self.snapshots.write(snapshot);
+    }
+
+    /// the next command or parameter change of the UI, never blocks
+    pub fn receive(&self) -> Option<UiInput> {
+        self.inputs.pop()
+    }
+}
+
+/// the UI's end of the link to the engine
+pub struct UiLink {
+    snapshots: Reader<Snapshot>,
+    sender: UiSender,
+}
+
+/// sends the input of a UI to the engine, every part of the UI that has one sends through the same queue
+#[derive(Clone)]
+pub struct UiSender {
+    inputs: Arc<Mutex<Producer<UiInput>>>,
+    // only UI threads lock it, the engine's end stays lock-free
+}
+
+impl UiSender {
+    /// send a command or parameter change to the engine, false if the queue is full
+    pub fn send(&self, input: UiInput) -> bool {
+        match self.inputs.lock() {
+            Ok(inputs) => inputs.push(input).is_ok(),
+            Err(_) => false,
+        }
+    }
+}
+
+impl UiLink {
+    /// the newest snapshot, None if no block was processed since the last call
+    pub fn latest(&self) -> Option<Snapshot> {
+        self.snapshots.read()
+    }
+
+    /// send a command or parameter change to the engine, false if the queue is full
+    pub fn send(&self, input: UiInput) -> bool {
+        self.sender.send(input)
+    }
+
+    /// another way into the engine's input queue, for buttons and keys
+    pub fn sender(&self) -> UiSender {
+        self.sender.clone()
+    }
+}
+
+/// create the two ends of a link between the engine and a UI
+pub fn link() -> (EngineLink, UiLink) {
+    let (snapshot_writer, snapshots) = triple_buffer::triple_buffer(Snapshot::default());
+    let (inputs, input_consumer) = spsc::channel(QUEUE_SIZE);
+    (EngineLink { snapshots: snapshot_writer, inputs: input_consumer }, UiLink { snapshots, sender: UiSender { inputs: Arc::new(Mutex::new(inputs)) } })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_link() {
+        let (engine, ui) = link();
+        assert!(ui.latest().is_none());
+        // more snapshots than a queue would have held, the UI didn't look in the meantime
+        for position in 0..100 {
+            let mut snapshot = Snapshot::default();
+            snapshot.play_position = position;
+            engine.publish(snapshot);
+        }
+        // only the newest one counts
+        assert_eq!(ui.latest().map(|s| s.play_position), Some(99));
+        assert!(ui.latest().is_none());
+
+        assert!(ui.send(UiInput::Command(Commands::Record)));
+        assert!(ui.sender().send(UiInput::Command(Commands::Play)));
+        assert_eq!(engine.receive(), Some(UiInput::Command(Commands::Record)));
+        assert_eq!(engine.receive(), Some(UiInput::Command(Commands::Play)));
+        assert_eq!(engine.receive(), None);
+    }
+
+    #[test]
+    fn test_loop_name() {
+        let mut snapshot = Snapshot::default();
+        assert_eq!(snapshot.loop_name(), "");
+        snapshot.set_loop_name("bass");
+        assert_eq!(snapshot.loop_name(), "bass");
+        // the cut doesn't split a character
+        let name: String = "ä".repeat(NAME_LEN);
+        snapshot.set_loop_name(&name);
+        assert_eq!(snapshot.loop_name().len(), NAME_LEN);
+        snapshot.set_loop_name(&format!("x{}", name));
+        assert_eq!(snapshot.loop_name().len(), NAME_LEN - 1);
+    }
+}
@@ -0,0 +1,127 @@
+// lock-free single producer / single consumer queue
+// used to pass messages between the audio thread and other threads without ever blocking the audio thread
+
+use std::cell::{Cell, UnsafeCell};
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+struct Queue<T> {
+    slots: Vec<UnsafeCell<Option<T>>>,
+    // next slot to read
+    head: AtomicUsize,
+    // next slot to write
+    tail: AtomicUsize,
+}
+
+// only one Producer writes and only one Consumer reads a slot at a time, the head and tail
+// indices make sure they never touch the same slot
+unsafe impl<T: Send> Sync for Queue<T> {}
+
+/// the sending half of a queue
+pub struct Producer<T> {
+    queue: Arc<Queue<T>>,
+    // there may only be one thread pushing
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+/// the receiving half of a queue
+pub struct Consumer<T> {
+    queue: Arc<Queue<T>>,
+    // there may only be one thread popping
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+/// create a queue that holds up to *capacity* values
+pub fn channel<T: Send>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    let capacity = capacity.max(1);
+    let mut slots = Vec::with_capacity(capacity);
+    for _ in 0..capacity {
+        slots.push(UnsafeCell::new(None));
+    }
+    let queue = Arc::new(Queue { slots, head: AtomicUsize::new(0), tail: AtomicUsize::new(0) });
+    (Producer { queue: queue.clone(), _not_sync: PhantomData },
+     Consumer { queue, _not_sync: PhantomData })
+}
+
+impl<T: Send> Producer<T> {
+    /// add a value to the queue, hands the value back if the queue is full
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let queue = &self.queue;
+        let tail = queue.tail.load(Ordering::Relaxed);
+        let head = queue.head.load(Ordering::Acquire);
+        if tail.wrapping_sub(head) == queue.slots.len() {
+            return Err(value);
+        }
+        unsafe {
+            *queue.slots[tail % queue.slots.len()].get() = Some(value);
+        }
+        queue.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+}
+
+impl<T: Send> Consumer<T> {
+    /// take the oldest value from the queue
+    pub fn pop(&self) -> Option<T> {
+        let queue = &self.queue;
+        let head = queue.head.load(Ordering::Relaxed);
+        let tail = queue.tail.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+        let value = unsafe { (*queue.slots[head % queue.slots.len()].get()).take() };
+        queue.head.store(head.wrapping_add(1), Ordering::Release);
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_fifo() {
+        let (producer, consumer) = channel(4);
+        producer.push(1).unwrap();
+        producer.push(2).unwrap();
+        assert_eq!(consumer.pop(), Some(1));
+        assert_eq!(consumer.pop(), Some(2));
+        assert_eq!(consumer.pop(), None);
+    }
+
+    #[test]
+    fn test_full() {
+        let (producer, consumer) = channel(2);
+        producer.push(1).unwrap();
+        producer.push(2).unwrap();
+        assert_eq!(producer.push(3), Err(3));
+        assert_eq!(consumer.pop(), Some(1));
+        producer.push(3).unwrap();
+        assert_eq!(consumer.pop(), Some(2));
+        assert_eq!(consumer.pop(), Some(3));
+    }
+
+    #[test]
+    fn test_across_threads() {
+        let (producer, consumer) = channel(16);
+        let sender = thread::spawn(move || {
+            for i in 0..1000 {
+                let mut value = i;
+                while let Err(v) = producer.push(value) {
+                    value = v;
+                    thread::yield_now();
+                }
+            }
+        });
+        let mut expected = 0;
+        while expected < 1000 {
+            if let Some(value) = consumer.pop() {
+                assert_eq!(value, expected);
+                expected += 1;
+            }
+        }
+        sender.join().unwrap();
+    }
+}
@@ -13,6 +13,6 @@ fn main() {
         size: Size { width: WIDTH, height: HEIGHT },
     }.build().expect("window to build correctly");
 
-    let _ = ui::PluginWindow::new(window);
+    let _ = ui::PluginWindow::new(window, None, None);
     let _ = App::run(); // start a cocoa runloop. not necessary on vsts.
 }
@@ -0,0 +1,61 @@
+// Stutter / beat repeat: while active, a slice of the current subdivision is repeated over and over.
+// The loop keeps running underneath, so playback resumes where it would have been without the stutter
+
+#[derive(Default)]
+pub struct Stutter {
+    pub active: bool,
+    // where in the recording does the repeated slice start
+    start: usize,
+    // length of the repeated slice in samples
+    length: usize,
+    // samples played since the start of the slice
+    elapsed: usize,
+}
+
+impl Stutter {
+    /// start repeating the slice of *length* samples at *start*, we are currently at *position*
+    pub fn start(&mut self, start: usize, length: usize, position: usize) {
+        if length == 0 {
+            return;
+        }
+        self.active = true;
+        self.start = start;
+        self.length = length;
+        self.elapsed = position.saturating_sub(start) % length;
+    }
+
+    pub fn stop(&mut self) {
+        self.active = false;
+    }
+
+    /// index into the recording of the sample *offset* samples after the current position
+    pub fn index(&self, offset: usize) -> usize {
+        if self.length == 0 {
+            return self.start;
+        }
+        self.start + (self.elapsed + offset) % self.length
+    }
+
+    /// advance the position inside the slice by *samples*
+    pub fn advance(&mut self, samples: usize) {
+        if self.length > 0 {
+            self.elapsed = (self.elapsed + samples) % self.length;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repeats_slice() {
+        let mut s = Stutter::default();
+        s.start(100, 10, 104);
+        assert!(s.active);
+        assert_eq!(s.index(0), 104);
+        assert_eq!(s.index(6), 100);
+        s.advance(16);
+        assert_eq!(s.index(0), 100);
+    }
+}
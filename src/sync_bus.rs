@@ -0,0 +1,233 @@
+// sync between the PlexLoopers in the same host (like the Brother Sync of the Echoplex)
+//
+// one instance is the master, it publishes its cycle on the bus after every block. Slaves start and stop
+// Record, Multiply and Insert on the master's cycle points, so their loops are multiples of the master's cycle.
+//
+// every instance counts its own samples, the bus keeps the master's count, a slave keeps the difference
+// to its own. As the host may run the master before or after a slave, that difference is only picked up
+// again when it moves by more than a block
+//
+// the master's clock and cycle are written together behind a sequence number (a seqlock), so a slave never
+// reads a new cycle length with the start of the old cycle
+
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// the id of the master, 0 when there is none
+static MASTER: AtomicUsize = AtomicUsize::new(0);
+// the master's clock at the end of its last block
+static MASTER_CLOCK: AtomicUsize = AtomicUsize::new(0);
+// the length of the master's cycle, 0 while it has no loop
+static CYCLE_LEN: AtomicUsize = AtomicUsize::new(0);
+// a time (master clock) a cycle of the master started at
+static CYCLE_START: AtomicUsize = AtomicUsize::new(0);
+// counts the writes of the three above, odd while one is under way
+static SEQUENCE: AtomicUsize = AtomicUsize::new(0);
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SyncRole {
+    Off,
+    Master,
+    Slave,
+}
+
+impl From<usize> for SyncRole {
+    fn from(val: usize) -> SyncRole {
+        match val {
+            1 => SyncRole::Master,
+            2 => SyncRole::Slave,
+            _ => SyncRole::Off,
+        }
+    }
+}
+
+impl fmt::Display for SyncRole {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let printable = match *self {
+            SyncRole::Off => "Off",
+            SyncRole::Master => "Master",
+            SyncRole::Slave => "Slave",
+        };
+        write!(f, "{}", printable)
+    }
+}
+
+pub struct SyncBus {
+    id: usize,
+    role: SyncRole,
+    offset: Option<i64>,
+    // master clock - own clock
+}
+
+impl Default for SyncBus {
+    fn default() -> SyncBus {
+        SyncBus { id: NEXT_ID.fetch_add(1, Ordering::SeqCst), role: SyncRole::Off, offset: None }
+    }
+}
+
+impl Drop for SyncBus {
+    fn drop(&mut self) {
+        self.set_role(SyncRole::Off);
+    }
+}
+
+impl SyncBus {
+    pub fn role(&self) -> SyncRole {
+        self.role
+    }
+
+    /// a new master takes over from the old one
+    pub fn set_role(&mut self, role: SyncRole) {
+        if role == SyncRole::Master {
+            MASTER.store(self.id, Ordering::SeqCst);
+        } else if MASTER.compare_exchange(self.id, 0, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+            let (clock, _, cycle_start) = read();
+            write(clock, 0, cycle_start);
+        }
+        self.role = role;
+        self.offset = None;
+    }
+
+    pub fn is_master(&self) -> bool {
+        self.role == SyncRole::Master && MASTER.load(Ordering::SeqCst) == self.id
+    }
+
+    /// the master tells the slaves where it is, at the end of a block
+    ///
+    /// *clock* is the time after the block, a cycle of *cycle_len* samples started at *cycle_start*
+    pub fn publish(&self, clock: usize, cycle_len: usize, cycle_start: usize) {
+        if !self.is_master() {
+            return;
+        }
+        write(clock, cycle_len, cycle_start);
+    }
+
+    /// a slave keeps up with the master at the start of a block of *samples* at *clock*
+    pub fn follow(&mut self, clock: usize, samples: usize) {
+        if self.role != SyncRole::Slave || MASTER.load(Ordering::SeqCst) == 0 {
+            self.offset = None;
+            return;
+        }
+        let (master_clock, _, _) = read();
+        let measured = master_clock as i64 - clock as i64;
+        match self.offset {
+            Some(offset) if (measured - offset).abs() <= samples as i64 => {}
+            _ => self.offset = Some(measured),
+        }
+    }
+
+    /// the length of the master's cycle, when this instance follows a master that has a loop
+    pub fn cycle_len(&self) -> Option<usize> {
+        let (_, cycle_len, _) = read();
+        match self.offset {
+            Some(_) if cycle_len > 0 => Some(cycle_len),
+            _ => None,
+        }
+    }
+
+    /// the first cycle point of the master at or after time *at* (own clock)
+    pub fn next_sync(&self, at: usize) -> Option<usize> {
+        let offset = self.offset?;
+        let (_, cycle_len, cycle_start) = read();
+        if cycle_len == 0 {
+            return None;
+        }
+        let cycle_len = cycle_len as i64;
+        let start = cycle_start as i64 - offset;
+        let since = ((at as i64 - start) % cycle_len + cycle_len) % cycle_len;
+        Some(if since == 0 { at } else { at + (cycle_len - since) as usize })
+    }
+}
+
+/// the master's clock, cycle length and cycle start, all from the same write
+fn read() -> (usize, usize, usize) {
+    loop {
+        let sequence = SEQUENCE.load(Ordering::SeqCst);
+        if sequence.is_multiple_of(2) {
+            let master = (
+                MASTER_CLOCK.load(Ordering::SeqCst),
+                CYCLE_LEN.load(Ordering::SeqCst),
+                CYCLE_START.load(Ordering::SeqCst),
+            );
+            if SEQUENCE.load(Ordering::SeqCst) == sequence {
+                return master;
+            }
+        }
+    }
+}
+
+/// write the master's clock and cycle, a master that is just leaving finishes its write first
+fn write(clock: usize, cycle_len: usize, cycle_start: usize) {
+    let mut sequence = SEQUENCE.load(Ordering::SeqCst);
+    loop {
+        if sequence % 2 == 1 {
+            sequence = SEQUENCE.load(Ordering::SeqCst);
+            continue;
+        }
+        match SEQUENCE.compare_exchange_weak(sequence, sequence + 1, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => break,
+            Err(current) => sequence = current,
+        }
+    }
+    MASTER_CLOCK.store(clock, Ordering::SeqCst);
+    CYCLE_LEN.store(cycle_len, Ordering::SeqCst);
+    CYCLE_START.store(cycle_start, Ordering::SeqCst);
+    SEQUENCE.store(sequence + 2, Ordering::SeqCst);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use looper_fsm::looper_cycle;
+    use {Commands, ELState, LooperState};
+
+    // the bus is shared by all instances, one test at a time uses it
+    static BUS: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn test_follow_master() {
+        let _bus = BUS.lock().unwrap();
+        let mut master = SyncBus::default();
+        let mut slave = SyncBus::default();
+        master.set_role(SyncRole::Master);
+        slave.set_role(SyncRole::Slave);
+        assert_eq!(slave.next_sync(0), None);
+
+        // the master is 1064 samples ahead, its cycle is 300 long and started at 900
+        master.publish(1064, 300, 900);
+        slave.follow(0, 64);
+        assert_eq!(slave.cycle_len(), Some(300));
+        // the cycle points 900, 1200, ... of the master are at -164, 136, ... for the slave
+        assert_eq!(slave.next_sync(10), Some(136));
+        // a block later nothing moves
+        master.publish(1128, 300, 900);
+        slave.follow(64, 64);
+        assert_eq!(slave.next_sync(136), Some(136));
+
+        master.set_role(SyncRole::Off);
+        slave.follow(128, 64);
+        assert_eq!(slave.next_sync(200), None);
+    }
+
+    #[test]
+    fn test_slave_records_into_empty_loop() {
+        let _bus = BUS.lock().unwrap();
+        let mut master = SyncBus::default();
+        master.set_role(SyncRole::Master);
+        master.publish(1064, 300, 900);
+
+        // an empty slave waits for the master's next cycle point to start recording
+        let mut state = ELState::default();
+        state.division = 8;
+        state.sync_bus.set_role(SyncRole::Slave);
+        state.sync_bus.follow(0, 64);
+        state.state = looper_cycle(&mut state, Commands::Record);
+        assert!(state.state == LooperState::SyncStart(Commands::Record));
+        assert_eq!(state.loop_length, 0);
+        state.advance(64);
+        assert_eq!(state.write_position, 0);
+        assert_eq!(state.play_position, 0);
+    }
+}
@@ -0,0 +1,135 @@
+// musical time of the loop: the tempo follows from the cycle length and how many beats there are in a cycle
+
+use std::fmt;
+
+/// ticks per beat in positions
+pub const TICKS_PER_BEAT: usize = 960;
+/// midi clock pulses per beat
+pub const CLOCK_PER_BEAT: usize = 24;
+
+/// a position as bar.beat.tick, bars and beats count from 1
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct BarBeatTick {
+    pub bar: usize,
+    pub beat: usize,
+    pub tick: usize,
+}
+
+impl fmt::Display for BarBeatTick {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{:03}", self.bar, self.beat, self.tick)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Tempo {
+    pub beats_per_cycle: usize,
+    pub beats_per_bar: usize,
+}
+
+impl Default for Tempo {
+    fn default() -> Tempo {
+        Tempo { beats_per_cycle: 4, beats_per_bar: 4 }
+    }
+}
+
+impl Tempo {
+    /// length of a beat in samples for a cycle of *cycle_len* samples
+    pub fn beat_len(&self, cycle_len: usize) -> f64 {
+        cycle_len as f64 / self.beats_per_cycle.max(1) as f64
+    }
+
+    /// beats per minute, 0 if there is no loop
+    pub fn bpm(&self, cycle_len: usize, sample_rate: f64) -> f64 {
+        if cycle_len == 0 {
+            return 0.;
+        }
+        60. * sample_rate / self.beat_len(cycle_len)
+    }
+
+    /// length of a loop of *loop_length* samples in bars
+    pub fn bars(&self, loop_length: usize, cycle_len: usize) -> f64 {
+        if cycle_len == 0 {
+            return 0.;
+        }
+        loop_length as f64 / self.beat_len(cycle_len) / self.beats_per_bar.max(1) as f64
+    }
+
+    /// the musical position of sample *position*
+    pub fn position(&self, position: usize, cycle_len: usize) -> BarBeatTick {
+        if cycle_len == 0 {
+            return BarBeatTick { bar: 1, beat: 1, tick: 0 };
+        }
+        let ticks = (position as f64 / self.beat_len(cycle_len) * TICKS_PER_BEAT as f64) as usize;
+        let beats = ticks / TICKS_PER_BEAT;
+        let beats_per_bar = self.beats_per_bar.max(1);
+        BarBeatTick {
+            bar: beats / beats_per_bar + 1,
+            beat: beats % beats_per_bar + 1,
+            tick: ticks % TICKS_PER_BEAT,
+        }
+    }
+
+    /// the offsets into a block of *samples* samples starting at *position* where a midi clock pulse falls,
+    /// the position wraps at *loop_length*
+    pub fn clock_pulses(&self, position: usize, samples: usize, loop_length: usize, cycle_len: usize,
+                        pulses: &mut Vec<usize>) {
+        pulses.clear();
+        if cycle_len == 0 || loop_length == 0 {
+            return;
+        }
+        let pulse_len = self.beat_len(cycle_len) / CLOCK_PER_BEAT as f64;
+        let mut start = position % loop_length;
+        let mut offset = 0;
+        while offset < samples {
+            // up to the end of the block or the end of the loop, whichever comes first
+            let end = (start + samples - offset).min(loop_length);
+            let mut pulse = (start as f64 / pulse_len).ceil();
+            while pulse * pulse_len < end as f64 {
+                let at = (pulse * pulse_len) as usize;
+                pulses.push(offset + at.max(start) - start);
+                pulse += 1.;
+            }
+            offset += end - start;
+            start = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bpm() {
+        let t = Tempo { beats_per_cycle: 8, beats_per_bar: 4 };
+        assert_eq!(t.bpm(0, 48000.), 0.);
+        assert_eq!(t.bpm(192000, 48000.), 120.);
+        assert_eq!(t.bars(384000, 192000), 4.);
+    }
+
+    #[test]
+    fn test_position() {
+        let t = Tempo::default();
+        // a beat is 1000 samples
+        assert_eq!(t.position(0, 4000).to_string(), "1.1.000");
+        assert_eq!(t.position(1500, 4000).to_string(), "1.2.480");
+        assert_eq!(t.position(9000, 4000).to_string(), "3.2.000");
+    }
+
+    #[test]
+    fn test_clock_pulses() {
+        let t = Tempo::default();
+        let mut pulses = Vec::new();
+        // a pulse every 100 samples
+        t.clock_pulses(0, 250, 9600, 9600, &mut pulses);
+        assert_eq!(pulses, vec![0, 100, 200]);
+        t.clock_pulses(250, 100, 9600, 9600, &mut pulses);
+        assert_eq!(pulses, vec![50]);
+        // wrapping at the end of the loop
+        t.clock_pulses(9550, 100, 9600, 9600, &mut pulses);
+        assert_eq!(pulses, vec![50]);
+        t.clock_pulses(9450, 200, 9600, 9600, &mut pulses);
+        assert_eq!(pulses, vec![50, 150]);
+    }
+}
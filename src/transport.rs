@@ -0,0 +1,163 @@
+// the transport buttons of the editor and the computer keyboard shortcuts
+//
+// both send their commands the way notes of the bindings do: a command goes when a key goes down, a held
+// command (Multiply, Replace, Insert) is released when the key comes up. A button can't be held, so a click
+// starts a held command and a click while the engine is still in it releases it
+
+use looper_fsm::{Commands, LooperState};
+
+/// the keys and their commands, upper and lower case are the same key
+pub const SHORTCUTS: [(char, Commands); 9] = [
+    ('r', Commands::Record),
+    ('p', Commands::Play),
+    ('s', Commands::Stop),
+    ('o', Commands::Overdub),
+    ('m', Commands::MultiplyStart),
+    ('e', Commands::ReplaceStart),
+    ('i', Commands::InsertStart),
+    ('u', Commands::Mute),
+    ('z', Commands::Undo),
+];
+
+/// the command of *key*, None if it isn't a shortcut
+pub fn shortcut(key: char) -> Option<Commands> {
+    let key = key.to_ascii_lowercase();
+    SHORTCUTS.iter().find(|&&(shortcut, _)| shortcut == key).map(|&(_, command)| command)
+}
+
+#[derive(Default)]
+pub struct Keys {
+    down: Vec<char>,
+    // the shortcut keys that are held, a key repeats while it is held but its command doesn't
+}
+
+impl Keys {
+    /// *key* went down, the command to send
+    pub fn down(&mut self, key: char) -> Option<Commands> {
+        let key = key.to_ascii_lowercase();
+        if self.down.contains(&key) {
+            return None;
+        }
+        let command = shortcut(key);
+        if command.is_some() {
+            self.down.push(key);
+        }
+        command
+    }
+
+    /// *key* came up, the command that releases what it held
+    pub fn up(&mut self, key: char) -> Option<Commands> {
+        let key = key.to_ascii_lowercase();
+        let index = self.down.iter().position(|&down| down == key)?;
+        self.down.remove(index);
+        shortcut(key).and_then(|command| command.release())
+    }
+}
+
+pub struct Toggles {
+    state: LooperState,
+    // the engine's state in the newest snapshot
+}
+
+impl Default for Toggles {
+    fn default() -> Toggles {
+        Toggles { state: LooperState::Stopped }
+    }
+}
+
+impl Toggles {
+    /// the engine is in *state*, whatever ended or started a held command
+    pub fn update(&mut self, state: LooperState) {
+        self.state = state;
+    }
+
+    /// the button of *command* was clicked, the command to send
+    pub fn click(&self, command: Commands) -> Commands {
+        if holds(self.state, command) {
+            command.release().unwrap_or(command)
+        } else {
+            command
+        }
+    }
+}
+
+/// is the engine in *state* still doing the held *command*?
+fn holds(state: LooperState, command: Commands) -> bool {
+    use looper_fsm::LooperState::*;
+    matches!((command, state),
+        (Commands::MultiplyStart, Multiplying) |
+        (Commands::MultiplyStart, SyncStart(Commands::MultiplyStart)) |
+        (Commands::ReplaceStart, Replacing) |
+        (Commands::ReplaceStart, SyncStart(Commands::ReplaceStart)) |
+        (Commands::InsertStart, Inserting) |
+        (Commands::InsertStart, Substituting) |
+        (Commands::InsertStart, SyncStart(Commands::InsertStart)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ELState;
+    use looper_fsm::looper_cycle;
+
+    #[test]
+    fn test_keys() {
+        let mut keys = Keys::default();
+        assert_eq!(keys.down('r'), Some(Commands::Record));
+        // the key repeats while it is held
+        assert_eq!(keys.down('r'), None);
+        assert_eq!(keys.up('r'), None);
+        assert_eq!(keys.down('R'), Some(Commands::Record));
+        assert_eq!(keys.up('R'), None);
+
+        assert_eq!(keys.down('e'), Some(Commands::ReplaceStart));
+        assert_eq!(keys.up('e'), Some(Commands::ReplaceStop));
+        assert_eq!(keys.up('e'), None);
+
+        assert_eq!(keys.down('x'), None);
+        assert_eq!(keys.up('x'), None);
+    }
+
+    #[test]
+    fn test_toggles() {
+        let mut toggles = Toggles::default();
+        assert_eq!(toggles.click(Commands::Overdub), Commands::Overdub);
+        assert_eq!(toggles.click(Commands::InsertStart), Commands::InsertStart);
+        toggles.update(LooperState::SyncStart(Commands::InsertStart));
+        assert_eq!(toggles.click(Commands::InsertStart), Commands::InsertStop);
+        assert_eq!(toggles.click(Commands::ReplaceStart), Commands::ReplaceStart);
+        toggles.update(LooperState::Replacing);
+        assert_eq!(toggles.click(Commands::ReplaceStart), Commands::ReplaceStop);
+        // Stop (or MIDI, or OSC) ended the replace, the next click starts a new one
+        toggles.update(LooperState::Stopped);
+        assert_eq!(toggles.click(Commands::ReplaceStart), Commands::ReplaceStart);
+    }
+
+    // like process does with a command
+    fn send(state: &mut ELState, command: Commands) {
+        state.prev_state = state.state;
+        state.state = looper_cycle(state, command);
+    }
+
+    #[test]
+    fn test_multiply() {
+        let mut state = ELState::default();
+        state.state = LooperState::Playing;
+        state.loop_length = 800;
+        state.cycle_len = 800;
+
+        let mut keys = Keys::default();
+        send(&mut state, keys.down('m').unwrap());
+        assert!(state.state == LooperState::Multiplying);
+        send(&mut state, keys.up('m').unwrap());
+        assert!(state.state == LooperState::Playing);
+
+        let mut toggles = Toggles::default();
+        toggles.update(state.state);
+        send(&mut state, toggles.click("MultiplyStart".parse().unwrap()));
+        assert!(state.state == LooperState::Multiplying);
+        toggles.update(state.state);
+        send(&mut state, toggles.click("MultiplyStart".parse().unwrap()));
+        assert!(state.state == LooperState::Playing);
+    }
+}
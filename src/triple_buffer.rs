@@ -0,0 +1,112 @@
+// lock-free triple buffer: a writer hands its newest value to a reader, a value that isn't read in time is
+// overwritten by the next one
+//
+// of the three slots the writer has one, the reader has one and the third is in between. Writing swaps the
+// writer's slot with the one in between, reading takes the one in between if a new value was put there
+
+use std::cell::{Cell, UnsafeCell};
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// set on the slot in between when the writer put a value there the reader hasn't taken yet
+const NEW: usize = 4;
+
+struct Slots<T> {
+    slots: [UnsafeCell<T>; 3],
+    middle: AtomicUsize,
+    // the slot in between, with the NEW bit
+}
+
+// the writer and the reader only touch their own slot, they swap slots through *middle*
+unsafe impl<T: Send> Sync for Slots<T> {}
+
+/// the writing half of a triple buffer
+pub struct Writer<T> {
+    slots: Arc<Slots<T>>,
+    slot: Cell<usize>,
+    // there may only be one thread writing
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+/// the reading half of a triple buffer
+pub struct Reader<T> {
+    slots: Arc<Slots<T>>,
+    slot: Cell<usize>,
+    // there may only be one thread reading
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+/// create a triple buffer, all slots start out as *initial*
+pub fn triple_buffer<T: Copy + Send>(initial: T) -> (Writer<T>, Reader<T>) {
+    let slots = Arc::new(Slots {
+        slots: [UnsafeCell::new(initial), UnsafeCell::new(initial), UnsafeCell::new(initial)],
+        middle: AtomicUsize::new(1),
+    });
+    (Writer { slots: slots.clone(), slot: Cell::new(0), _not_sync: PhantomData },
+     Reader { slots, slot: Cell::new(2), _not_sync: PhantomData })
+}
+
+impl<T: Copy + Send> Writer<T> {
+    /// hand *value* to the reader, in place of one it didn't read yet
+    pub fn write(&self, value: T) {
+        let slot = self.slot.get();
+        unsafe {
+            *self.slots.slots[slot].get() = value;
+        }
+        let middle = self.slots.middle.swap(slot | NEW, Ordering::AcqRel);
+        self.slot.set(middle & !NEW);
+    }
+}
+
+impl<T: Copy + Send> Reader<T> {
+    /// the newest value, None if nothing was written since the last read
+    pub fn read(&self) -> Option<T> {
+        if self.slots.middle.load(Ordering::Acquire) & NEW == 0 {
+            return None;
+        }
+        let middle = self.slots.middle.swap(self.slot.get(), Ordering::AcqRel);
+        let slot = middle & !NEW;
+        self.slot.set(slot);
+        Some(unsafe { *self.slots.slots[slot].get() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_latest() {
+        let (writer, reader) = triple_buffer(0);
+        assert_eq!(reader.read(), None);
+        writer.write(1);
+        writer.write(2);
+        writer.write(3);
+        assert_eq!(reader.read(), Some(3));
+        assert_eq!(reader.read(), None);
+        writer.write(4);
+        assert_eq!(reader.read(), Some(4));
+    }
+
+    #[test]
+    fn test_across_threads() {
+        let (writer, reader) = triple_buffer((0, 0));
+        let sender = thread::spawn(move || {
+            for i in 1..10000 {
+                writer.write((i, 2 * i));
+            }
+        });
+        let mut last = 0;
+        while last < 9999 {
+            if let Some((i, double)) = reader.read() {
+                // never torn, never older than what was read before
+                assert_eq!(double, 2 * i);
+                assert!(i > last);
+                last = i;
+            }
+        }
+        sender.join().unwrap();
+    }
+}
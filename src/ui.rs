@@ -1,10 +1,40 @@
+use std::sync::{Arc, Mutex};
+
 use tinyui::*;
 
+/// the loops the UI can pick from
+pub trait LoopList {
+    fn names(&self) -> Vec<String>;
+    fn load(&self, index: usize);
+}
+
+pub type SharedLoopList = Arc<Mutex<LoopList + Send>>;
+
+/// where the transport buttons send their commands
+pub trait Transport {
+    // *command* is the name of a looper command, like in the bindings
+    fn click(&mut self, command: &str);
+}
+
+pub type SharedTransport = Arc<Mutex<Transport + Send>>;
+
+// the command and the text of the transport buttons
+const TRANSPORT: [(&str, &str); 9] = [
+    ("Record", "Rec"),
+    ("Play", "Play"),
+    ("Stop", "Stop"),
+    ("Overdub", "Dub"),
+    ("MultiplyStart", "Mult"),
+    ("ReplaceStart", "Repl"),
+    ("InsertStart", "Ins"),
+    ("Mute", "Mute"),
+    ("Undo", "Undo"),
+];
 
 const WIDTH: f64 = 480.;
-const HEIGHT: f64 = 160.;
+const HEIGHT: f64 = 190.;
 
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 pub struct PluginWindow {
     window: Window,
    // button: Button,
@@ -14,6 +44,16 @@ pub struct PluginWindow {
     pub division_label: Label,
     pub subdiv_label: Label,
     pub version_label: Label,
+    pub mode_label: Label,
+    library_label: Label,
+    library_prev: Button,
+    library_next: Button,
+    library_load: Button,
+    library: Option<SharedLoopList>,
+    selected: usize,
+    // the loop of the library that is shown
+    transport_buttons: Vec<Button>,
+    transport: Option<SharedTransport>,
 }
 
 impl EventHandler for PluginWindow {
@@ -23,7 +63,24 @@ impl EventHandler for PluginWindow {
             Event::ButtonClicked(name) => {
                 match name.as_str() {
                    //  "a button" => { self.button.set_text("clicked me"); }
-                    _ => {}
+                    "library prev" => self.select(-1),
+                    "library next" => self.select(1),
+                    "library load" => {
+                        if let Some(ref library) = self.library {
+                            if let Ok(library) = library.lock() {
+                                library.load(self.selected);
+                            }
+                        }
+                    }
+                    name => {
+                        if let Some(ref transport) = self.transport {
+                            if TRANSPORT.iter().any(|&(command, _)| command == name) {
+                                if let Ok(mut transport) = transport.lock() {
+                                    transport.click(name);
+                                }
+                            }
+                        }
+                    }
                 }
             }
             // Event::WindowWillClose => App::quit(), // don't do this on a vst
@@ -34,7 +91,7 @@ impl EventHandler for PluginWindow {
 }
 
 impl PluginWindow {
-    pub fn new(mut window: Window) -> Self {
+    pub fn new(mut window: Window, library: Option<SharedLoopList>, transport: Option<SharedTransport>) -> Self {
         let window_rect = Rect::new(0., 0., WIDTH, HEIGHT);
         let (_top_half_rect, _bottom_half_rect) = window_rect.split_horizontal();
         // info!("building window");
@@ -46,6 +103,35 @@ impl PluginWindow {
             subdiv_label: Label::new("x", Rect::new(10., 100., 480., 40.)),
             state_label: Label::new("Stopped", Rect::new(10., 50., 180., 40. )),
             version_label: Label::new("PlexLooper v0000", Rect::new(380., 10., 120., 16.)),
+            mode_label: Label::new("Insert", Rect::new(380., 30., 100., 16.)),
+            library_label: Label::new("", Rect::new(45., 140., 250., 16.)),
+            library_prev: ButtonBuilder {
+                id: "library prev",
+                text: "<",
+                style: ButtonStyle::Square,
+                position: Rect::new(10., 138., 30., 20.),
+            }.build(),
+            library_next: ButtonBuilder {
+                id: "library next",
+                text: ">",
+                style: ButtonStyle::Square,
+                position: Rect::new(300., 138., 30., 20.),
+            }.build(),
+            library_load: ButtonBuilder {
+                id: "library load",
+                text: "Load",
+                style: ButtonStyle::Square,
+                position: Rect::new(335., 138., 60., 20.),
+            }.build(),
+            library,
+            selected: 0,
+            transport_buttons: TRANSPORT.iter().enumerate().map(|(i, &(command, text))| ButtonBuilder {
+                id: command,
+                text,
+                style: ButtonStyle::Square,
+                position: Rect::new(10. + 52. * i as f64, 165., 50., 20.),
+            }.build()).collect(),
+            transport,
 //            button: ButtonBuilder {
 //                id: "a button",
 //                text: "click me",
@@ -66,6 +152,8 @@ impl PluginWindow {
         app.subdiv_label.set_font(font);
         app.state_label.set_font(font);
         app.version_label.set_font(version_font);
+        app.mode_label.set_font(version_font);
+        app.library_label.set_font(version_font);
 
         app.counter.attach(&mut app.window);
         app.cycle_label.attach(&mut app.window);
@@ -73,10 +161,34 @@ impl PluginWindow {
         app.subdiv_label.attach(&mut app.window);
         app.state_label.attach(&mut app.window);
         app.version_label.attach(&mut app.window);
+        app.mode_label.attach(&mut app.window);
+        app.library_label.attach(&mut app.window);
+        app.library_prev.attach(&mut app.window);
+        app.library_next.attach(&mut app.window);
+        app.library_load.attach(&mut app.window);
+        for button in app.transport_buttons.iter_mut() {
+            button.attach(&mut app.window);
+        }
+        app.select(0);
         app.version_label.set_text("Version v0.0.1.1");
         app.window.set_handler(app.clone());
 
         app
     }
 
+    /// show the loop *step* places on in the library
+    fn select(&mut self, step: isize) {
+        let names = match self.library {
+            Some(ref library) => library.lock().map(|library| library.names()).unwrap_or_default(),
+            None => Vec::new(),
+        };
+        if names.is_empty() {
+            self.library_label.set_text("no loops in the library");
+            return;
+        }
+        let count = names.len() as isize;
+        self.selected = ((self.selected as isize + step) % count + count) as usize % names.len();
+        self.library_label.set_text(&format!("{}: {}", self.selected, names[self.selected]));
+    }
+
 }